@@ -11,8 +11,10 @@ use std::io::prelude::*;
 use std::fs::File;
 use std::path::Path;
 
-use rs_regex::reinterp::ThompsonInterpreter;
-use rs_regex::reinterp::TokenizerAction;
+use rs_regex::reinterp::{ThompsonInterpreter, MatchEngine, Token, UnmatchedPolicy};
+use rs_regex::retoken::{Action, ActionResult, Tokenizer};
+use rs_regex::relazy::LazyDfa;
+use rs_regex::redfa::{DfaBuilder, DfaInterpreter};
 
 /**
  * The interpreter is actually a Thompson VM partially applied to a
@@ -22,23 +24,29 @@ use rs_regex::reinterp::TokenizerAction;
  */
 struct RegexTokenizer {
 
-    interpreter: rs_regex::reinterp::ThompsonInterpreter,
+    interpreter: Tokenizer<Box<dyn MatchEngine>, TokenCounts>,
 
 }
 
 impl RegexTokenizer {
 
-    fn run(&mut self, text: &str) {
-        self.interpreter.apply(text);
+    fn run<R: Read>(&mut self, input: R) -> TokenCounts {
+        let mut counts = TokenCounts::default();
+        if let Err(e) = self.interpreter.apply_reader(&mut counts, input) {
+            println!("{}", e);
+        }
+        counts
     }
 
 }
 
 
+type CountingAction = Box<dyn FnMut(&mut TokenCounts, &Token) -> ActionResult>;
+
 struct TokenizerBuilder {
     compiler: rs_regex::retrans::RegexTranslator,
     rule_nbr: usize,
-    actions: Vec<TokenizerAction>,
+    actions: Vec<CountingAction>,
 }
 
 impl TokenizerBuilder {
@@ -51,31 +59,73 @@ impl TokenizerBuilder {
         }
     }
 
+    /**
+     * Compile the rules to match bytes of UTF-8 rather than characters.
+     * Must come before the first rule.
+     */
+    fn byte_mode(mut self, byte_mode: bool) -> TokenizerBuilder {
+        self.compiler.set_byte_mode(byte_mode);
+        self
+    }
+
     /**
      * This should compile the pattern and add to the current program.
      */
-    fn add_rule(
+    fn add_rule<A: Action<TokenCounts> + 'static>(
         mut self, 
         pattern: &str, 
-        action: TokenizerAction,
+        mut action: A,
     ) -> TokenizerBuilder {
-        let tree = rs_regex::reparse::parse(pattern);
+        let tree = match rs_regex::reparse::parse(pattern) {
+            Ok(t) => t,
+            Err(e) => {
+                println!("{}", e);
+                process::exit(1);
+            }
+        };
         println!("{}", tree);
-        self.compiler.compile(&tree, self.rule_nbr);
-        // TODO: The action should be added to an action list here.
-        self.actions.push(action);
+        if let Err(e) = self.compiler.compile(&tree, self.rule_nbr) {
+            println!("{}", e);
+            process::exit(1);
+        }
+        self.actions.push(Box::new(move |counts, tok| action.run(counts, tok)));
 
         self.rule_nbr += 1;
         self
     }
 
-    fn done(mut self) -> RegexTokenizer {
+    fn done(mut self, engine: Engine) -> RegexTokenizer {
         self.compiler.finish();       // ground instruction labels
         self.compiler.print_prog();
-        RegexTokenizer {
-            interpreter: ThompsonInterpreter::new(self.compiler.prog,
-                                                  self.actions),
+        println!("{}", self.compiler.prog.stats());
+        let interpreter: Box<dyn MatchEngine> = match engine {
+            Engine::Nfa => {
+                Box::new(ThompsonInterpreter::new(self.compiler.prog, vec![]))
+            }
+            Engine::LazyDfa => {
+                Box::new(LazyDfa::new(self.compiler.prog, vec![]))
+            }
+            Engine::Dfa => {
+                let mut dfa = match DfaBuilder::new().build(&self.compiler.prog) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        println!("{}", e);
+                        process::exit(1);
+                    }
+                };
+                println!("{}", dfa.minimize());
+                println!("DFA: {} states, {} character classes",
+                         dfa.state_count(), dfa.class_count());
+                Box::new(DfaInterpreter::new(dfa, vec![]))
+            }
+        };
+        let mut tokenizer = Tokenizer::new(interpreter);
+        for action in self.actions {
+            tokenizer.add_action(action);
         }
+        tokenizer.set_unmatched_policy(UnmatchedPolicy::Coalesce);
+        tokenizer.set_unmatched_action(unmatched_action);
+        RegexTokenizer { interpreter: tokenizer }
     }
 
 }
@@ -85,14 +135,25 @@ impl TokenizerBuilder {
 
 
 
+#[derive(Clone, Copy)]
+enum Engine {
+    Nfa,
+    LazyDfa,
+    Dfa,
+}
+
 struct AppConfig {
     text_file: Option<String>,
+    engine: Engine,
+    byte_mode: bool,
 }
 
 impl AppConfig {
     fn new() -> AppConfig {
         AppConfig { 
             text_file: None,
+            engine: Engine::Nfa,
+            byte_mode: false,
         }
     }
 }
@@ -102,9 +163,12 @@ fn configure() -> AppConfig {
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this message and exit");
     opts.optopt("f", "file", "match text from file", "NAME");
+    opts.optflag("l", "lazy-dfa", "match with the lazy DFA instead of the NFA");
+    opts.optflag("d", "dfa", "match with a fully built DFA instead of the NFA");
+    opts.optflag("b", "bytes", "compile the rules to match bytes instead of characters");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+        Err(f) => { panic!("{}", f) }
     };
     if matches.opt_present("h") {
         print_usage(&args[0], &opts);
@@ -112,6 +176,12 @@ fn configure() -> AppConfig {
 
     let mut cfg: AppConfig = AppConfig::new();
     cfg.text_file = matches.opt_str("f");
+    if matches.opt_present("d") {
+        cfg.engine = Engine::Dfa;
+    } else if matches.opt_present("l") {
+        cfg.engine = Engine::LazyDfa;
+    }
+    cfg.byte_mode = matches.opt_present("b");
 
     cfg
 }
@@ -124,56 +194,64 @@ fn print_usage(program: &str, opts: &Options) {
 
 
 
-struct TextSource {
-    text: String,
+/**
+ * The text to match against, from a file or stdin. It is read a chunk
+ * at a time as the tokenizer goes, so it can be as big as you like.
+ */
+fn open_text(cfg: &AppConfig) -> Box<dyn Read> {
+    match cfg.text_file {
+        None => Box::new(io::stdin()),
+        Some(ref fname) => {
+            let fpath = Path::new(&fname);
+            Box::new(File::open(fpath).unwrap())
+        }
+    }
 }
 
-impl TextSource {
-    pub fn new(cfg: &AppConfig) -> TextSource {
-        // Get the text to match against (from file or stdin)
-        let mut txt = String::new();
-        match cfg.text_file {
-            None => {
-                let stdin = io::stdin();
-                stdin.lock().read_to_string(&mut txt).unwrap();
-            },
-            Some(ref fname) => {
-                let fpath = Path::new(&fname);
-                let mut f = File::open(fpath).unwrap();
-                f.read_to_string(&mut txt).unwrap();
-            }
-        }  
+#[derive(Default)]
+struct TokenCounts {
+    words: usize,
+    numbers: usize,
+    punct: usize,
+    unmatched: usize,
+}
 
-        TextSource { text: txt }      
-    }
+fn word_action(counts: &mut TokenCounts, tok: &Token) {
+    counts.words += 1;
+    println!("WORD [{}]", tok.text);
+}
 
-    pub fn get_text(&self) -> &str {
-        &self.text
-    }
+fn num_action(counts: &mut TokenCounts, tok: &Token) {
+    counts.numbers += 1;
+    println!("NUMBER [{}]", tok.text);
 }
 
-fn word_action (tok: &str) {
-    println!("WORD [{}]", tok);
+fn punct_action(counts: &mut TokenCounts, tok: &Token) {
+    counts.punct += 1;
+    println!("PUNCT [{}]", tok.text);
 }
 
-fn num_action(tok: &str) {
-    println!("NUMBER [{}]", tok);
+fn unmatched_action(counts: &mut TokenCounts, tok: &Token) {
+    counts.unmatched += 1;
+    println!("UNMATCHED [{}]", tok.text);
 }
 
-fn punct_action(tok: &str) {
-    println!("PUNCT [{}]", tok);
+fn space_action(_: &mut TokenCounts, _: &Token) -> ActionResult {
+    ActionResult::Skip
 }
 
 fn main() {
     let cfg = configure();
-    let text_src = TextSource::new(&cfg);
 
     let mut english_tokenizer = TokenizerBuilder::new()
+        .byte_mode(cfg.byte_mode)
         .add_rule(r"(?i)[a-z]+", word_action)           // [0] words
         .add_rule(r"[0-9,.]*[0-9]+", num_action)        // [1] numbers
         .add_rule(r"[.,?!]", punct_action)              // [2] punctuation
-        .done();
+        .add_rule(r"\s+", space_action)                 // [3] whitespace
+        .done(cfg.engine);
 
-    println!("\n{}", text_src.get_text());
-    english_tokenizer.run(text_src.get_text());
+    let counts = english_tokenizer.run(open_text(&cfg));
+    println!("\n{} words, {} numbers, {} punctuation marks, {} unmatched",
+             counts.words, counts.numbers, counts.punct, counts.unmatched);
 }
@@ -38,7 +38,7 @@ fn configure() -> AppConfig {
     opts.optopt("f", "file", "match text from file", "NAME");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+        Err(f) => { panic!("{}", f) }
     };
     if matches.opt_present("h") {
         print_usage(&args[0], &opts);
@@ -111,7 +111,7 @@ impl RegexSource {
             if l1.is_empty() {
                 continue;
             }
-            if l1.chars().next() == Some('#') {
+            if l1.starts_with('#') {
                 continue;
             }
             println!("> {}", l1);
@@ -128,15 +128,27 @@ impl RegexSource {
 fn test(regex_src: &RegexSource, text_src: &TextSource) {
     use rs_regex::reparse::parse;
     use rs_regex::retrans::RegexTranslator;
-    use rs_regex::reinterp::ThompsonInterpreter;
+    use rs_regex::reinterp::{ThompsonInterpreter, MatchEngine};
     use rs_regex::reinterp::TokenizerAction;
+    use rs_regex::relines::with_positions;
 
     let mut rule_nbr: usize = 0;
     let mut translator = RegexTranslator::new();
     for regex in &regex_src.regexes {
-        let tree = parse(&regex);
+        let tree = match parse(regex) {
+            Ok(t) => t,
+            Err(e) => {
+                // Report the bad rule, but keep going with the others.
+                println!("{}", e);
+                continue;
+            }
+        };
         println!("{}", tree);
-        translator.compile(&tree, rule_nbr);  // extend current program
+        // extend current program
+        if let Err(e) = translator.compile(&tree, rule_nbr) {
+            println!("{}", e);
+            continue;
+        }
         rule_nbr += 1;
     }
 
@@ -146,10 +158,18 @@ fn test(regex_src: &RegexSource, text_src: &TextSource) {
 
     let actions : Vec<TokenizerAction> = vec![];
     let mut interpreter = ThompsonInterpreter::new(translator.prog, actions);
-    let text = &text_src.get_text();
+    let text = text_src.get_text();
     println!("{}", text);
-    interpreter.apply(&text);
-    if interpreter.matches.len() == 0 {
+    for located in with_positions(text, interpreter.tokens(text)) {
+        let tok = located.token;
+        let at = format!("{}:{}", located.start.line, located.start.column);
+        if tok.is_unmatched() {
+            println!("UNMATCHED: {} {} -> {} {:?}", at, tok.start, tok.end, tok.text);
+        } else {
+            println!("TOKEN: {} {} -> {} [{}] {:?}", at, tok.start, tok.end, tok.rule, tok.text);
+        }
+    }
+    if interpreter.matches.is_empty() {
         println!("There were no matches");
     } else {
         for m in interpreter.matches {
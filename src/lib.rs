@@ -1,8 +1,22 @@
 pub mod reparse;
 pub mod retrans;
 pub mod reinterp;
+pub mod relazy;
+pub mod redfa;
+pub mod retoken;
+pub mod relines;
+pub mod restream;
+pub mod rebytes;
+mod rescan;
 
 mod reterm;
 pub mod reprog;
 mod sparse;
+mod unicode_tables;
+mod utf8;
 mod util;
+#[cfg(test)]
+mod testutil;
+
+/// The Unicode version that '\p{...}' classes are built from.
+pub use unicode_tables::UNICODE_VERSION;
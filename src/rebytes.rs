@@ -0,0 +1,357 @@
+/*
+ * Tokenizing bytes that may not be valid UTF-8.
+ *
+ * The engines only work on text, so invalid UTF-8 has to be dealt with
+ * before they see it. InvalidUtf8 says how: give up, make each invalid
+ * sequence something only '\I' matches (and a token of its own where
+ * no rule does), or read it as U+FFFD, the replacement character. An
+ * invalid sequence is as much of the input as String::from_utf8_lossy()
+ * would replace with one U+FFFD.
+ *
+ * Under InvalidUtf8::Token, the engines run over a copy of the input
+ * with each invalid byte replaced by SUB (U+001A), which keeps offsets
+ * into the text the same as offsets into the bytes. The SUBs are only
+ * placeholders: the engines are told where the invalid sequences are
+ * (MatchEngine::all_matches_at_decoded()), and nothing but '\I' matches
+ * there. Assertions see the text around them as it is, so '^' does not
+ * match just after one, and '\b' takes one for a non-word character.
+ * A token that takes in an invalid sequence has its SUBs in its text,
+ * and says where they are (Token::invalid), so they can be told apart
+ * from real ones.
+ */
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::iter;
+use std::str;
+use reinterp::{ConditionError, MatchEngine, NoMatchError, Token, Tokens, UnmatchedPolicy, INVALID};
+use rescan::Progress;
+use restream::OwnedToken;
+use retoken::TokenizeError;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InvalidUtf8 {
+    /// Fail with a Utf8Error before any tokens, if there is any invalid
+    /// UTF-8 at all.
+    #[default]
+    Strict,
+    /// Only '\I' matches an invalid sequence. Where no rule does, it is
+    /// a token of its own, with rule INVALID and text U+FFFD. Token
+    /// offsets are into the bytes. Tokens of rules that match one have
+    /// a placeholder in their text for each of its bytes: see
+    /// Token::invalid.
+    Token,
+    /// Each invalid sequence reads as U+FFFD, which rules can match
+    /// like any other character (but '\I' does not: there is nothing
+    /// invalid left). Token offsets are then into the text from
+    /// String::from_utf8_lossy(), not into the bytes.
+    Replace,
+}
+
+/**
+ * The input is not valid UTF-8, from byte offset `pos` on, and the
+ * policy was InvalidUtf8::Strict.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Utf8Error {
+    pub pos: usize,
+}
+
+impl fmt::Display for Utf8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid UTF-8 at position {}", self.pos)
+    }
+}
+
+impl Error for Utf8Error {}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BytesError {
+    InvalidUtf8(Utf8Error),
+    /// No rule matches the input, under UnmatchedPolicy::Stop.
+    NoMatch(NoMatchError),
+    /// An action asked for a start condition the program does not have.
+    NoCondition(ConditionError),
+}
+
+impl fmt::Display for BytesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BytesError::InvalidUtf8(ref e) => write!(f, "{}", e),
+            BytesError::NoMatch(ref e) => write!(f, "{}", e),
+            BytesError::NoCondition(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for BytesError {}
+
+impl From<Utf8Error> for BytesError {
+    fn from(e: Utf8Error) -> BytesError {
+        BytesError::InvalidUtf8(e)
+    }
+}
+
+impl From<NoMatchError> for BytesError {
+    fn from(e: NoMatchError) -> BytesError {
+        BytesError::NoMatch(e)
+    }
+}
+
+impl From<TokenizeError> for BytesError {
+    fn from(e: TokenizeError) -> BytesError {
+        match e {
+            TokenizeError::NoMatch(e) => BytesError::NoMatch(e),
+            TokenizeError::NoCondition(e) => BytesError::NoCondition(e),
+        }
+    }
+}
+
+
+/**
+ * The input, ready to tokenize: the text for the engines, and the
+ * (start, end) byte ranges of the invalid sequences in it, in order.
+ * Only InvalidUtf8::Token leaves any.
+ */
+pub(crate) struct Decoded<'b> {
+    pub(crate) text: Cow<'b, str>,
+    pub(crate) invalid: Vec<(usize, usize)>,
+}
+
+/**
+ * Decode `bytes`, as `invalid` says.
+ */
+pub(crate) fn decode(bytes: &[u8], invalid: InvalidUtf8) -> Result<Decoded<'_>, Utf8Error> {
+    let text = match (str::from_utf8(bytes), invalid) {
+        (Ok(text), _) => Cow::Borrowed(text),
+        (Err(e), InvalidUtf8::Strict) => return Err(Utf8Error { pos: e.valid_up_to() }),
+        (Err(_), InvalidUtf8::Token) => {
+            let mut text = String::with_capacity(bytes.len());
+            let mut spans = vec![];
+            for chunk in bytes.utf8_chunks() {
+                text.push_str(chunk.valid());
+                let len = chunk.invalid().len();
+                if len > 0 {
+                    spans.push((text.len(), text.len() + len));
+                    text.extend(iter::repeat_n(INVALID_BYTE, len));
+                }
+            }
+            return Ok(Decoded { text: Cow::Owned(text), invalid: spans });
+        }
+        (Err(_), InvalidUtf8::Replace) => String::from_utf8_lossy(bytes),
+    };
+    Ok(Decoded { text, invalid: vec![] })
+}
+
+/**
+ * What each byte of an invalid sequence reads as in the text from
+ * decode(): SUB, ASCII's own replacement character. It is one byte
+ * long, like the byte it replaces.
+ */
+pub(crate) const INVALID_BYTE: char = '\u{1A}';
+
+/**
+ * Where the invalid sequence starting at byte offset `pos` ends, if
+ * there is one.
+ */
+pub(crate) fn invalid_at(invalid: &[(usize, usize)], pos: usize) -> Option<usize> {
+    invalid.binary_search_by_key(&pos, |&(start, _)| start).ok().map(|i| invalid[i].1)
+}
+
+/**
+ * An offset into the text from decode(), moved on past any invalid
+ * sequence it falls inside, so that it does not split one.
+ */
+pub(crate) fn past_invalid(invalid: &[(usize, usize)], pos: usize) -> usize {
+    let i = invalid.partition_point(|&(_, end)| end <= pos);
+    match invalid.get(i) {
+        Some(&(start, end)) if start < pos => end,
+        _ => pos,
+    }
+}
+
+/**
+ * The invalid sequences inside text[start..end].
+ */
+pub(crate) fn invalid_within(invalid: &[(usize, usize)], start: usize, end: usize) -> &[(usize, usize)] {
+    let from = invalid.partition_point(|&(s, _)| s < start);
+    let to = invalid.partition_point(|&(s, _)| s < end);
+    &invalid[from..to]
+}
+
+/**
+ * The INVALID token for the invalid sequence text[start..end].
+ */
+pub(crate) fn invalid_token<'t>(start: usize, end: usize) -> Token<'t> {
+    Token { rule: INVALID, start, end, text: INVALID_TEXT, invalid: &[] }
+}
+
+/**
+ * The text of INVALID tokens.
+ */
+pub(crate) const INVALID_TEXT: &str = "\u{FFFD}";
+
+
+/**
+ * Iterator over the tokens of some bytes, as reinterp::Tokens would
+ * find them in text. Invalid UTF-8 is dealt with as the InvalidUtf8
+ * policy says. Iteration ends after the first error.
+ */
+pub struct ByteTokens<'e, 'b, E: 'e + MatchEngine + ?Sized> {
+    engine: &'e mut E,
+    bytes: &'b [u8],
+    invalid: InvalidUtf8,
+    policy: UnmatchedPolicy,
+    input: Option<Decoded<'b>>,     // worked out on the first call to next()
+    progress: Progress,
+    done: bool,
+}
+
+impl<'e, 'b, E: 'e + MatchEngine + ?Sized> ByteTokens<'e, 'b, E> {
+    pub fn new(engine: &'e mut E, bytes: &'b [u8]) -> ByteTokens<'e, 'b, E> {
+        ByteTokens {
+            engine,
+            bytes,
+            invalid: InvalidUtf8::default(),
+            policy: UnmatchedPolicy::default(),
+            input: None,
+            progress: Progress::default(),
+            done: false,
+        }
+    }
+
+    pub fn with_invalid_utf8(mut self, invalid: InvalidUtf8) -> ByteTokens<'e, 'b, E> {
+        self.invalid = invalid;
+        self
+    }
+
+    pub fn with_unmatched_policy(mut self, policy: UnmatchedPolicy) -> ByteTokens<'e, 'b, E> {
+        self.policy = policy;
+        self
+    }
+}
+
+impl<'e, 'b, E: 'e + MatchEngine + ?Sized> Iterator for ByteTokens<'e, 'b, E> {
+    type Item = Result<OwnedToken, BytesError>;
+
+    fn next(&mut self) -> Option<Result<OwnedToken, BytesError>> {
+        if self.done {
+            return None;
+        }
+        if self.input.is_none() {
+            match decode(self.bytes, self.invalid) {
+                Ok(input) => { self.input = Some(input); }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            }
+        }
+        let input = self.input.as_ref().unwrap();
+        let (tok, error) = {
+            let mut tokens = Tokens::new(&mut *self.engine, &input.text)
+                .with_unmatched_policy(self.policy)
+                .with_invalid(&input.invalid)
+                .resume(self.progress);
+            let tok = tokens.next().map(OwnedToken::from);
+            self.progress = tokens.progress();
+            (tok, self.progress.error())
+        };
+        if let Some(e) = error {
+            self.done = true;
+            return Some(Err(e.into()));
+        }
+        if tok.is_none() {
+            self.done = true;
+        }
+        tok.map(Ok)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reinterp::{Token, UNMATCHED};
+    use relazy::LazyDfa;
+    use retoken::Tokenizer;
+    use testutil::{compile, engines};
+
+    const RULES: &[&str] = &[r"^b", r"\bc", r"a\Ib", r"\I\I", r"\w+", r"\s+"];
+
+    fn byte_tokens<E: MatchEngine + ?Sized>(engine: &mut E, bytes: &[u8], invalid: InvalidUtf8)
+        -> Vec<(usize, usize, usize)>
+    {
+        ByteTokens::new(engine, bytes)
+            .with_invalid_utf8(invalid)
+            .map(|tok| tok.map(|tok| (tok.rule, tok.start, tok.end)).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn only_invalid_sequences_match_backslash_i() {
+        // 0xff and a 0xc3 cut short are two invalid sequences; then a
+        // real U+FFFD, which '\I' must leave alone.
+        let bytes = b"a\xffb\xff\xc3 \xef\xbf\xbd";
+        let want = vec![(2, 0, 3), (3, 3, 5), (5, 5, 6), (UNMATCHED, 6, 9)];
+        for mut engine in engines(RULES) {
+            assert_eq!(byte_tokens(&mut engine, bytes, InvalidUtf8::Token), want);
+            assert_eq!(byte_tokens(&mut engine, "\u{FFFD}".as_bytes(), InvalidUtf8::Strict),
+                       vec![(UNMATCHED, 0, 3)]);
+        }
+    }
+
+    #[test]
+    fn assertions_see_across_invalid_sequences() {
+        // Only the first 'b' is at the start, and 'c' follows a non-word.
+        let bytes = b"b\xffb\xffc";
+        let want = vec![(0, 0, 1), (INVALID, 1, 2), (4, 2, 3), (INVALID, 3, 4), (1, 4, 5)];
+        for mut engine in engines(RULES) {
+            assert_eq!(byte_tokens(&mut engine, bytes, InvalidUtf8::Token), want);
+        }
+    }
+
+    #[test]
+    fn tokenizer_agrees() {
+        let bytes = b"a\xffb\xff\xc3 b\xe2\x82c \xef\xbf\xbd";
+        let mut tokenizer = Tokenizer::new(LazyDfa::new(compile(RULES), vec![]));
+        for _ in RULES {
+            tokenizer.add_action(|seen: &mut Vec<(usize, usize, usize)>, tok: &Token| {
+                seen.push((tok.rule, tok.start, tok.end));
+            });
+        }
+        tokenizer.set_unmatched_action(|seen: &mut Vec<(usize, usize, usize)>, tok: &Token| {
+            seen.push((tok.rule, tok.start, tok.end));
+        });
+        tokenizer.set_invalid_utf8(InvalidUtf8::Token);
+        let mut seen = vec![];
+        tokenizer.apply_bytes(&mut seen, bytes).unwrap();
+        let mut engine = LazyDfa::new(compile(RULES), vec![]);
+        assert_eq!(seen, byte_tokens(&mut engine, bytes, InvalidUtf8::Token));
+    }
+
+
+    #[test]
+    fn tokens_say_where_their_invalid_sequences_are() {
+        // A real SUB, then 0xe2 0x82, one invalid sequence of two bytes
+        // that reads as two SUBs.
+        let bytes = b"a\x1ab\xe2\x82c \xff";
+        for mut engine in engines(&[r"[a-z]([a-z\x1a]|\I)*", " "]) {
+            let tokens: Vec<OwnedToken> = ByteTokens::new(&mut engine, bytes)
+                .with_invalid_utf8(InvalidUtf8::Token)
+                .map(|tok| tok.unwrap())
+                .collect();
+            let word = tokens[0].as_token();
+            assert_eq!(word.text, "a\u{1a}b\u{1a}\u{1a}c");
+            assert_eq!(word.invalid, &[(3, 5)]);
+            assert_eq!(word.lossy_text(), String::from_utf8_lossy(&bytes[..6]));
+            assert_eq!(word.lossy_text(), "a\u{1a}b\u{FFFD}c");
+            // The space, and the INVALID token, have none.
+            assert!(tokens[1].invalid.is_empty() && tokens[2].invalid.is_empty());
+            assert_eq!(tokens[2].rule, INVALID);
+        }
+    }
+}
@@ -0,0 +1,1074 @@
+/*
+ * Ahead-of-time DFA.
+ *
+ * The lazy DFA in relazy.rs builds states as the text asks for them.
+ * Here we build all of them up front, by subset construction over the
+ * whole program, so that the tables can be written out once and loaded
+ * again at start-up without parsing or compiling any regexes.
+ *
+ * States are the lazy DFA's states (relazy::StateKey), so everything
+ * said there about assertions, the one-step delay in reporting matches,
+ * and the extra transition at the end of the text holds here too.
+ *
+ * The alphabet is all of Unicode, so the transition table can not have
+ * a column per character. Instead, characters are grouped into classes
+ * that no instruction in the program can tell apart, and the table has
+ * a column per class (plus one for the end of the text, and one for an
+ * invalid UTF-8 sequence).
+ *
+ * Programs compiled in byte mode give a DFA over bytes instead, where
+ * the classes are the program's byte classes. Those can not use '\b' or '\B' next to
+ * non-ASCII characters (see relazy.rs), so building one may fail with
+ * DfaError::WordBoundaryInBytes.
+ */
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use reprog::*;
+use reprog::Instruction::*;
+use reinterp::{ConditionError, MatchEngine, MatchRecord, TokenizerAction};
+use relazy::{Context, Input, Scratch, StateKey, input_at, step};
+use reterm::{CharClassData, NamedClass};
+
+
+/**
+ * Default maximum number of states the builder will create.
+ */
+pub const DEFAULT_STATE_LIMIT: usize = 100_000;
+
+/**
+ * Version of the binary table format written by Dfa::to_bytes().
+ */
+pub const FORMAT_VERSION: u32 = 1;
+
+const MAGIC: &[u8; 4] = b"RXDF";
+
+type StateId = usize;
+
+
+#[derive(Debug)]
+pub enum DfaError {
+    /// Subset construction needed more than `limit` states.
+    TooManyStates { limit: usize },
+    /// A trailing context rule r/s where neither r nor s has a fixed
+    /// length. Only the NFA engines can tell where r ends in those.
+    VariableTrailingContext { rule: usize },
+    /// A byte mode program where '\b' or '\B' would have to look at a
+    /// non-ASCII character, which a byte DFA can not decode.
+    WordBoundaryInBytes,
+    /// The table does not start with the expected magic number.
+    BadMagic,
+    /// The table was written in a format version we can not read.
+    UnsupportedVersion(u32),
+    /// The table's contents do not match its checksum.
+    BadChecksum,
+    /// The table ended early.
+    Truncated,
+    /// The table passed its checksum, but makes no sense.
+    Corrupt(&'static str),
+    Io(io::Error),
+}
+
+impl fmt::Display for DfaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DfaError::TooManyStates { limit } => {
+                write!(f, "DFA would have more than {} states", limit)
+            }
+            DfaError::VariableTrailingContext { rule } => {
+                write!(f, "rule {} has trailing context, but neither part has a fixed length",
+                       rule)
+            }
+            DfaError::WordBoundaryInBytes => {
+                write!(f, "a byte DFA can not tell whether a non-ASCII character is a word character")
+            }
+            DfaError::BadMagic => write!(f, "not a DFA table"),
+            DfaError::UnsupportedVersion(v) => {
+                write!(f, "DFA table format version {} is not supported (expected {})",
+                       v, FORMAT_VERSION)
+            }
+            DfaError::BadChecksum => write!(f, "DFA table checksum mismatch"),
+            DfaError::Truncated => write!(f, "DFA table is truncated"),
+            DfaError::Corrupt(what) => write!(f, "DFA table is corrupt: {}", what),
+            DfaError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for DfaError {}
+
+impl From<io::Error> for DfaError {
+    fn from(e: io::Error) -> DfaError {
+        DfaError::Io(e)
+    }
+}
+
+
+/**
+ * A partition of the characters into classes. Stored as runs of
+ * consecutive characters: run i starts at starts[i] and runs up to the
+ * next start, and all of its characters are in class classes[i]. For a
+ * byte mode DFA, the "characters" are the bytes, U+0000 to U+00FF.
+ */
+#[derive(Debug, Clone)]
+struct Alphabet {
+    starts: Vec<char>,
+    classes: Vec<usize>,
+    count: usize,
+    ascii: [usize; 128],
+}
+
+/**
+ * Everything the program can find out about a character by testing it.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Signature {
+    literal: Option<usize>,     // index into the Char instructions' chars
+    word: bool,                 // needed for \b, \B and Context
+    classes: Vec<bool>,         // result of each distinct class test
+}
+
+impl Alphabet {
+
+    /**
+     * Group the characters by their signature for this program. Every
+     * test is a set of ranges, so the signature can only change where
+     * one of them starts or ends, and we test one character from each
+     * stretch between those points.
+     */
+    fn new(prog: &Program) -> Alphabet {
+        // '\n' is special for '.' and for Context, whatever the program says.
+        let mut literals: Vec<char> = vec!['\n'];
+        let mut tests: Vec<&CharClassData> = vec![];
+        for pc in 0..prog.len() {
+            match prog[pc] {
+                Char(ref data) => literals.push(data.ch),
+                CharClass(ref ccd) if !tests.contains(&&ccd.data) => {
+                    tests.push(&ccd.data);
+                }
+                _ => {}
+            }
+        }
+        literals.sort();
+        literals.dedup();
+
+        // The surrogates are not characters, so they get a stretch of
+        // their own, which we skip.
+        let mut bounds: Vec<u32> = vec![0, 0xD800, 0xE000, 0x11_0000];
+        let mut add_ranges = |ranges: &[(char, char)]| {
+            for &(lo, hi) in ranges {
+                bounds.push(lo as u32);
+                bounds.push(hi as u32 + 1);
+            }
+        };
+        for &ch in &literals {
+            add_ranges(&[(ch, ch)]);
+        }
+        add_ranges(&NamedClass::Word.char_ranges());
+        for t in &tests {
+            add_ranges(&t.char_ranges());
+        }
+        bounds.sort();
+        bounds.dedup();
+
+        let mut starts = vec![];
+        let mut classes = vec![];
+        let mut ids: HashMap<Signature, usize> = HashMap::new();
+        let mut sig = Signature { literal: None, word: false, classes: vec![] };
+        let mut prev: Option<Signature> = None;
+        for ch in bounds.iter().filter_map(|&b| ::std::char::from_u32(b)) {
+            sig.literal = literals.binary_search(&ch).ok();
+            sig.word = NamedClass::Word.matches(ch);
+            sig.classes.clear();
+            sig.classes.extend(tests.iter().map(|t| t.matches(ch)));
+            if prev.as_ref() == Some(&sig) {
+                continue;
+            }
+            let next_id = ids.len();
+            let id = *ids.entry(sig.clone()).or_insert(next_id);
+            starts.push(ch);
+            classes.push(id);
+            prev = Some(sig.clone());
+        }
+        Alphabet::from_runs(starts, classes, ids.len())
+    }
+
+    /**
+     * The alphabet of a byte mode program: its byte classes.
+     */
+    fn bytes(prog: &Program) -> Alphabet {
+        let byte_classes = prog.byte_classes();
+        let mut starts = vec![];
+        let mut classes = vec![];
+        for b in 0..=255u8 {
+            let class = byte_classes.get(b);
+            if classes.last() != Some(&class) {
+                starts.push(b as char);
+                classes.push(class);
+            }
+        }
+        Alphabet::from_runs(starts, classes, byte_classes.count())
+    }
+
+    fn from_runs(starts: Vec<char>, classes: Vec<usize>, count: usize) -> Alphabet {
+        let mut alphabet = Alphabet { starts, classes, count, ascii: [0; 128] };
+        for b in 0..128u8 {
+            alphabet.ascii[b as usize] = alphabet.lookup(b as char);
+        }
+        alphabet
+    }
+
+    fn lookup(&self, ch: char) -> usize {
+        match self.starts.binary_search(&ch) {
+            Ok(i) => self.classes[i],
+            Err(i) => self.classes[i - 1],
+        }
+    }
+
+    fn class_of(&self, ch: char) -> usize {
+        if (ch as u32) < 128 {
+            self.ascii[ch as usize]
+        } else {
+            self.lookup(ch)
+        }
+    }
+
+    /**
+     * One character from each class, in class order.
+     */
+    fn representatives(&self) -> Vec<char> {
+        let mut reps = vec![None; self.count];
+        for (&ch, &class) in self.starts.iter().zip(&self.classes) {
+            if reps[class].is_none() {
+                reps[class] = Some(ch);
+            }
+        }
+        reps.into_iter().map(|c| c.unwrap()).collect()
+    }
+}
+
+
+#[derive(Debug, Clone)]
+struct DfaState {
+    matches: Vec<usize>,        // rules that matched just before getting here
+    next: Vec<StateId>,         // one per character class, then end of text, then invalid
+    dead_end: bool,             // no more matches can be found from here
+}
+
+/**
+ * The result of Dfa::minimize().
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Minimization {
+    pub before: usize,
+    pub after: usize,
+}
+
+impl fmt::Display for Minimization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "minimized DFA from {} to {} states", self.before, self.after)
+    }
+}
+
+/**
+ * A complete DFA for a program, with one transition per state and
+ * character class. Build it with DfaBuilder, or load it from tables
+ * written by to_bytes(), and run it with DfaInterpreter.
+ */
+#[derive(Debug, Clone)]
+pub struct Dfa {
+    alphabet: Alphabet,
+    byte_mode: bool,            // the alphabet is bytes, not characters
+    rule_count: usize,
+    conditions: Vec<String>,    // start condition names
+    trailing: Vec<(usize, TrailInfo)>,  // trailing context rules, by rule id
+    starts: Vec<[StateId; 4]>,  // indexed by start condition, then Context
+    states: Vec<DfaState>,
+}
+
+impl Dfa {
+
+    pub fn state_count(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn class_count(&self) -> usize {
+        self.alphabet.count
+    }
+
+    /**
+     * The number of rules in the program this was built from.
+     */
+    pub fn rule_count(&self) -> usize {
+        self.rule_count
+    }
+
+    /**
+     * Look up a start condition by name, as for Program::condition_id().
+     */
+    pub fn condition_id(&self, name: &str) -> Option<usize> {
+        self.conditions.iter().position(|sc| sc == name)
+    }
+
+    pub fn condition_count(&self) -> usize {
+        self.conditions.len()
+    }
+
+    fn trail_info(&self, rule: usize) -> Option<TrailInfo> {
+        self.trailing.binary_search_by_key(&rule, |&(r, _)| r)
+            .ok()
+            .map(|i| self.trailing[i].1)
+    }
+
+    fn start_state(&self, sc: usize, context: Context) -> StateId {
+        self.starts[sc][context as usize]
+    }
+
+    /**
+     * Whether this DFA steps on bytes; see Program::byte_mode().
+     */
+    pub fn byte_mode(&self) -> bool {
+        self.byte_mode
+    }
+
+    fn next_state(&self, from: StateId, input: Input) -> StateId {
+        let column = match input {
+            Input::Char(c) => self.alphabet.class_of(c),
+            Input::Byte(b) => self.alphabet.class_of(b as char),
+            Input::End => self.alphabet.count,
+            Input::Invalid => self.alphabet.count + 1,
+        };
+        self.states[from].next[column]
+    }
+
+    /**
+     * Work out which states can not lead to any more matches, so the
+     * interpreter can stop as soon as it reaches one.
+     */
+    fn mark_dead_ends(&mut self) {
+        let mut preds: Vec<Vec<StateId>> = vec![vec![]; self.states.len()];
+        for (from, st) in self.states.iter().enumerate() {
+            for &to in &st.next {
+                preds[to].push(from);
+            }
+        }
+        let mut live = vec![false; self.states.len()];
+        let mut agenda: Vec<StateId> = (0..self.states.len())
+            .filter(|&s| !self.states[s].matches.is_empty())
+            .collect();
+        while let Some(s) = agenda.pop() {
+            for &p in &preds[s] {
+                if !live[p] {
+                    live[p] = true;
+                    agenda.push(p);
+                }
+            }
+        }
+        for (st, live) in self.states.iter_mut().zip(live) {
+            st.dead_end = !live;
+        }
+    }
+
+    /**
+     * Merge equivalent states, by Hopcroft's partition refinement.
+     * States start out grouped by the rules they report, so two states
+     * that accept different rules are never merged, even where the
+     * lengths agree.
+     */
+    pub fn minimize(&mut self) -> Minimization {
+        let before = self.states.len();
+        let symbols = self.alphabet.count + 2;
+
+        // For each symbol, the states that go to each state on it.
+        let inverse: Vec<Vec<Vec<StateId>>> = (0..symbols)
+            .map(|sym| {
+                let mut preds = vec![vec![]; before];
+                for (from, st) in self.states.iter().enumerate() {
+                    preds[st.next[sym]].push(from);
+                }
+                preds
+            })
+            .collect();
+
+        let mut blocks: Vec<Vec<StateId>> = vec![];
+        let mut block_of: Vec<usize> = vec![0; before];
+        let mut by_rules: HashMap<&[usize], usize> = HashMap::new();
+        for (s, st) in self.states.iter().enumerate() {
+            let b = *by_rules.entry(&st.matches).or_insert_with(|| {
+                blocks.push(vec![]);
+                blocks.len() - 1
+            });
+            blocks[b].push(s);
+            block_of[s] = b;
+        }
+
+        let mut agenda: Vec<usize> = (0..blocks.len()).collect();
+        let mut on_agenda = vec![true; blocks.len()];
+        let mut marked = vec![false; before];
+        while let Some(splitter) = agenda.pop() {
+            on_agenda[splitter] = false;
+            let members = blocks[splitter].clone();
+            for preds in &inverse {
+                // Which blocks have states going into the splitter?
+                let mut touched: Vec<usize> = vec![];
+                let mut hits: HashMap<usize, Vec<StateId>> = HashMap::new();
+                for &to in &members {
+                    for &from in &preds[to] {
+                        if !marked[from] {
+                            marked[from] = true;
+                            let b = block_of[from];
+                            hits.entry(b).or_insert_with(|| {
+                                touched.push(b);
+                                vec![]
+                            }).push(from);
+                        }
+                    }
+                }
+                for b in touched {
+                    let inside = hits.remove(&b).unwrap();
+                    let split = inside.len() < blocks[b].len();
+                    if split {
+                        blocks[b].retain(|&s| !marked[s]);
+                    }
+                    for &s in &inside {
+                        marked[s] = false;
+                    }
+                    if !split {
+                        continue;
+                    }
+                    let nb = blocks.len();
+                    for &s in &inside {
+                        block_of[s] = nb;
+                    }
+                    blocks.push(inside);
+                    if on_agenda[b] {
+                        on_agenda.push(true);
+                        agenda.push(nb);
+                    } else {
+                        let smaller = if blocks[nb].len() < blocks[b].len() { nb } else { b };
+                        on_agenda.push(smaller == nb);
+                        on_agenda[b] = smaller == b;
+                        agenda.push(smaller);
+                    }
+                }
+            }
+        }
+
+        let states = blocks.iter()
+            .map(|members| {
+                let st = &self.states[members[0]];
+                DfaState {
+                    matches: st.matches.clone(),
+                    next: st.next.iter().map(|&to| block_of[to]).collect(),
+                    dead_end: false,
+                }
+            })
+            .collect();
+        for s in self.starts.iter_mut().flat_map(|row| row.iter_mut()) {
+            *s = block_of[*s];
+        }
+        self.states = states;
+        self.mark_dead_ends();
+        Minimization { before, after: self.states.len() }
+    }
+
+    /**
+     * Serialize the tables. All numbers are 32 bit little endian:
+     *
+     * ```text
+     * magic "RXDF", version, rule count, 1 for byte mode or 0,
+     * start condition count, then for each start condition its
+     *   name's length in bytes and the UTF-8 name itself,
+     * trailing context rule count, then (rule id, length of r, length
+     *   of s) for each, where an unknown length is 0xffffffff,
+     * run count, then (first char or byte, class) for each run,
+     * class count, state count,
+     * for each start condition, the start state for each Context,
+     * for each state: match count, rule ids, next state for each
+     *   class, for the end of the text and for an invalid sequence,
+     * checksum (FNV-1a of everything before it)
+     * ```
+     */
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out: Vec<u8> = MAGIC.to_vec();
+        put_u32(&mut out, FORMAT_VERSION);
+        put_u32(&mut out, self.rule_count as u32);
+        put_u32(&mut out, self.byte_mode as u32);
+        put_u32(&mut out, self.conditions.len() as u32);
+        for name in &self.conditions {
+            put_u32(&mut out, name.len() as u32);
+            out.extend_from_slice(name.as_bytes());
+        }
+        put_u32(&mut out, self.trailing.len() as u32);
+        for &(rule, info) in &self.trailing {
+            put_u32(&mut out, rule as u32);
+            put_u32(&mut out, info.head_len.map_or(NO_LENGTH, |n| n as u32));
+            put_u32(&mut out, info.tail_len.map_or(NO_LENGTH, |n| n as u32));
+        }
+        put_u32(&mut out, self.alphabet.starts.len() as u32);
+        for (&ch, &class) in self.alphabet.starts.iter().zip(&self.alphabet.classes) {
+            put_u32(&mut out, ch as u32);
+            put_u32(&mut out, class as u32);
+        }
+        put_u32(&mut out, self.alphabet.count as u32);
+        put_u32(&mut out, self.states.len() as u32);
+        for &s in self.starts.iter().flat_map(|row| row.iter()) {
+            put_u32(&mut out, s as u32);
+        }
+        for st in &self.states {
+            put_u32(&mut out, st.matches.len() as u32);
+            for &rule in &st.matches {
+                put_u32(&mut out, rule as u32);
+            }
+            for &to in &st.next {
+                put_u32(&mut out, to as u32);
+            }
+        }
+        let sum = checksum(&out);
+        put_u32(&mut out, sum);
+        out
+    }
+
+    pub fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.to_bytes())
+    }
+
+    /**
+     * Rebuild a DFA from tables written by to_bytes(). Everything is
+     * checked, so bad input gives an error rather than a DFA that
+     * panics later on.
+     */
+    pub fn from_bytes(bytes: &[u8]) -> Result<Dfa, DfaError> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            return Err(DfaError::BadMagic);
+        }
+        let mut rd = TableReader { bytes, pos: MAGIC.len() };
+        let version = rd.u32()?;
+        if version != FORMAT_VERSION {
+            return Err(DfaError::UnsupportedVersion(version));
+        }
+        if bytes.len() < rd.pos + 4 {
+            return Err(DfaError::Truncated);
+        }
+        let body_len = bytes.len() - 4;
+        let stored = TableReader { bytes, pos: body_len }.u32()?;
+        if checksum(&bytes[..body_len]) != stored {
+            return Err(DfaError::BadChecksum);
+        }
+        let mut rd = TableReader { bytes: &bytes[..body_len], pos: rd.pos };
+
+        let rule_count = rd.usize()?;
+        let byte_mode = match rd.u32()? {
+            0 => false,
+            1 => true,
+            _ => return Err(DfaError::Corrupt("bad mode")),
+        };
+        let condition_count = rd.usize()?;
+        if condition_count == 0 {
+            return Err(DfaError::Corrupt("no start conditions"));
+        }
+        let mut conditions = Vec::with_capacity(condition_count.min(rd.remaining() / 4));
+        for _ in 0..condition_count {
+            let len = rd.usize()?;
+            let name = ::std::str::from_utf8(rd.bytes(len)?)
+                .map_err(|_| DfaError::Corrupt("bad start condition name"))?;
+            conditions.push(name.to_string());
+        }
+        let trailing_count = rd.usize()?;
+        let mut trailing = Vec::with_capacity(trailing_count.min(rd.remaining() / 12));
+        for _ in 0..trailing_count {
+            let rule = rd.usize()?;
+            if rule >= rule_count || trailing.last().is_some_and(|&(last, _)| rule <= last) {
+                return Err(DfaError::Corrupt("bad trailing context rule"));
+            }
+            let info = TrailInfo { head_len: rd.length()?, tail_len: rd.length()? };
+            if info.head_len.is_none() && info.tail_len.is_none() {
+                return Err(DfaError::Corrupt("bad trailing context rule"));
+            }
+            trailing.push((rule, info));
+        }
+        let run_count = rd.usize()?;
+        let mut starts = Vec::with_capacity(run_count.min(rd.remaining() / 8));
+        let mut classes = Vec::with_capacity(starts.capacity());
+        for _ in 0..run_count {
+            let ch = ::std::char::from_u32(rd.u32()?)
+                .filter(|&c| !byte_mode || (c as u32) < 256)
+                .ok_or(DfaError::Corrupt("bad character"))?;
+            if starts.last().map_or(ch != '\0', |&last| ch <= last) {
+                return Err(DfaError::Corrupt("character runs out of order"));
+            }
+            starts.push(ch);
+            classes.push(rd.usize()?);
+        }
+        // Every class has a run, and every state a column per class,
+        // so a class count the runs or the rest of the table can not
+        // back up is corrupt, not something to allocate for.
+        let class_count = rd.usize()?;
+        if classes.iter().max().map(|&c| c + 1) != Some(class_count)
+            || class_count > rd.remaining() / 4 {
+            return Err(DfaError::Corrupt("bad character class"));
+        }
+        let state_count = rd.usize()?;
+        let mut start_states = Vec::with_capacity(condition_count.min(rd.remaining() / 16));
+        for _ in 0..condition_count {
+            let mut row = [0; 4];
+            for s in &mut row {
+                *s = rd.state(state_count)?;
+            }
+            start_states.push(row);
+        }
+        let mut states = Vec::with_capacity(state_count.min(rd.remaining() / 4));
+        for _ in 0..state_count {
+            let match_count = rd.usize()?;
+            let mut matches = Vec::with_capacity(match_count.min(rd.remaining() / 4));
+            for _ in 0..match_count {
+                let rule = rd.usize()?;
+                if rule >= rule_count {
+                    return Err(DfaError::Corrupt("bad rule id"));
+                }
+                matches.push(rule);
+            }
+            let mut next = Vec::with_capacity((class_count + 2).min(rd.remaining() / 4));
+            for _ in 0..class_count + 2 {
+                next.push(rd.state(state_count)?);
+            }
+            states.push(DfaState { matches, next, dead_end: false });
+        }
+        if rd.remaining() != 0 {
+            return Err(DfaError::Corrupt("trailing bytes"));
+        }
+
+        let mut dfa = Dfa {
+            alphabet: Alphabet::from_runs(starts, classes, class_count),
+            byte_mode,
+            rule_count,
+            conditions,
+            trailing,
+            starts: start_states,
+            states,
+        };
+        dfa.mark_dead_ends();
+        Ok(dfa)
+    }
+
+    pub fn read_from<R: Read>(r: &mut R) -> Result<Dfa, DfaError> {
+        let mut bytes = vec![];
+        r.read_to_end(&mut bytes)?;
+        Dfa::from_bytes(&bytes)
+    }
+}
+
+/**
+ * Stands for an unknown length in the trailing context table.
+ */
+const NO_LENGTH: u32 = u32::MAX;
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    out.extend_from_slice(&n.to_le_bytes());
+}
+
+/**
+ * 32 bit FNV-1a.
+ */
+fn checksum(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for &b in bytes {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+struct TableReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> TableReader<'a> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DfaError> {
+        if self.remaining() < len {
+            return Err(DfaError::Truncated);
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> Result<u32, DfaError> {
+        let mut word = [0; 4];
+        word.copy_from_slice(self.bytes(4)?);
+        Ok(u32::from_le_bytes(word))
+    }
+
+    fn usize(&mut self) -> Result<usize, DfaError> {
+        self.u32().map(|n| n as usize)
+    }
+
+    fn length(&mut self) -> Result<Option<usize>, DfaError> {
+        self.u32().map(|n| if n == NO_LENGTH { None } else { Some(n as usize) })
+    }
+
+    fn state(&mut self, state_count: usize) -> Result<StateId, DfaError> {
+        let s = self.usize()?;
+        if s >= state_count {
+            return Err(DfaError::Corrupt("bad state id"));
+        }
+        Ok(s)
+    }
+}
+
+
+pub struct DfaBuilder {
+    state_limit: usize,
+}
+
+impl Default for DfaBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DfaBuilder {
+    pub fn new() -> DfaBuilder {
+        DfaBuilder { state_limit: DEFAULT_STATE_LIMIT }
+    }
+
+    /**
+     * Give up with DfaError::TooManyStates rather than build a DFA with
+     * more than this many states.
+     */
+    pub fn set_state_limit(&mut self, limit: usize) {
+        self.state_limit = limit;
+    }
+
+    /**
+     * Subset construction, starting from all four start contexts in
+     * each start condition.
+     */
+    pub fn build(&self, prog: &Program) -> Result<Dfa, DfaError> {
+        let mut trailing = vec![];
+        for rule in prog.trailing_rules() {
+            let (_, info) = prog.trailing(rule).unwrap();
+            if info.head_len.is_none() && info.tail_len.is_none() {
+                return Err(DfaError::VariableTrailingContext { rule });
+            }
+            trailing.push((rule, info));
+        }
+
+        let byte_mode = prog.byte_mode();
+        let alphabet = if byte_mode { Alphabet::bytes(prog) } else { Alphabet::new(prog) };
+        let inputs: Vec<Input> = alphabet.representatives().into_iter()
+            .map(|c| if byte_mode { Input::Byte(c as u8) } else { Input::Char(c) })
+            .chain([Input::End, Input::Invalid])
+            .collect();
+
+        let mut keys: Vec<StateKey> = vec![];
+        let mut index: HashMap<StateKey, StateId> = HashMap::new();
+        let mut starts = vec![];
+        for sc in 0..prog.conditions.len() {
+            let threads = prog.condition_starts(sc);
+            let mut row = [0; 4];
+            for &context in &Context::ALL {
+                let key = StateKey::start(&threads, context);
+                row[context as usize] = self.add_state(key, &mut keys, &mut index)?;
+            }
+            starts.push(row);
+        }
+
+        let mut scratch = Scratch::new(prog.len());
+        let mut states = vec![];
+        while states.len() < keys.len() {
+            let key = keys[states.len()].clone();
+            let mut next = Vec::with_capacity(inputs.len());
+            for &input in &inputs {
+                let to = step(prog, &mut scratch, &key, input)
+                    .ok_or(DfaError::WordBoundaryInBytes)?;
+                next.push(self.add_state(to, &mut keys, &mut index)?);
+            }
+            states.push(DfaState { matches: key.matches, next, dead_end: false });
+        }
+
+        let rule_count = (0..prog.len())
+            .filter_map(|pc| match prog[pc] {
+                Match(ref data) => Some(data.rule_id + 1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let conditions = prog.conditions.iter().map(|sc| sc.name.clone()).collect();
+        let mut dfa = Dfa { alphabet, byte_mode, rule_count, conditions, trailing, starts, states };
+        dfa.mark_dead_ends();
+        Ok(dfa)
+    }
+
+    fn add_state(
+        &self,
+        key: StateKey,
+        keys: &mut Vec<StateKey>,
+        index: &mut HashMap<StateKey, StateId>,
+    ) -> Result<StateId, DfaError> {
+        if let Some(&id) = index.get(&key) {
+            return Ok(id);
+        }
+        if keys.len() >= self.state_limit {
+            return Err(DfaError::TooManyStates { limit: self.state_limit });
+        }
+        let id = keys.len();
+        index.insert(key.clone(), id);
+        keys.push(key);
+        Ok(id)
+    }
+}
+
+
+pub struct DfaInterpreter {
+    pub matches: Vec<MatchRecord>,
+    dfa: Dfa,
+    actions: Vec<TokenizerAction>,
+    condition: usize,
+    hit_end: bool,
+}
+
+impl DfaInterpreter {
+    pub fn new(dfa: Dfa, acts: Vec<TokenizerAction>) -> DfaInterpreter {
+        DfaInterpreter {
+            matches: vec![],
+            dfa,
+            actions: acts,
+            condition: INITIAL,
+            hit_end: false,
+        }
+    }
+
+    pub fn dfa(&self) -> &Dfa {
+        &self.dfa
+    }
+}
+
+impl MatchEngine for DfaInterpreter {
+
+    fn all_matches_at_decoded(&mut self, text: &str, start: usize, invalid: &[(usize, usize)]) {
+        self.matches.clear();
+
+        let context = Context::of(text[..start].chars().next_back());
+        let mut state = self.dfa.start_state(self.condition, context);
+        let mut pos = start;
+        let mut ahead = &invalid[invalid.partition_point(|&(_, end)| end <= start)..];
+        loop {
+            let (input, len) = input_at(text, pos, self.dfa.byte_mode, ahead);
+            state = self.dfa.next_state(state, input);
+
+            // As in the lazy DFA, these are the matches at pos.
+            let dfa_state = &self.dfa.states[state];
+            for &rule in &dfa_state.matches {
+                self.matches.push(MatchRecord::new(pos - start, rule));
+            }
+            if input == Input::End || dfa_state.dead_end {
+                self.hit_end = input == Input::End;
+                break;
+            }
+            if input == Input::Invalid {
+                ahead = &ahead[1..];
+            }
+            pos += len;
+        }
+
+        // The builder only allows trailing context it can split here.
+        for m in &mut self.matches {
+            if let Some(info) = self.dfa.trail_info(m.rule) {
+                let len = info.fixed_split(&text[start..start + m.len]).unwrap();
+                *m = m.with_token_len(len);
+            }
+        }
+    }
+
+    fn matches(&self) -> &[MatchRecord] {
+        &self.matches
+    }
+
+    fn hit_end(&self) -> bool {
+        self.hit_end
+    }
+
+    fn action(&self, rule: usize) -> TokenizerAction {
+        self.actions[rule]
+    }
+
+    fn set_start_condition(&mut self, sc: usize) -> Result<(), ConditionError> {
+        if sc >= self.dfa.conditions.len() {
+            return Err(ConditionError { condition: sc });
+        }
+        self.condition = sc;
+        Ok(())
+    }
+
+    /**
+     * As for the lazy DFA: run to the end of the text, or to a dead
+     * end, and see whether anything matches there.
+     */
+    fn is_match_any(&mut self, text: &str) -> bool {
+        let mut state = self.dfa.start_state(self.condition, Context::of(None));
+        let mut pos = 0;
+        loop {
+            let (input, len) = input_at(text, pos, self.dfa.byte_mode, &[]);
+            state = self.dfa.next_state(state, input);
+            let dfa_state = &self.dfa.states[state];
+            if input == Input::End {
+                return !dfa_state.matches.is_empty();
+            }
+            if dfa_state.dead_end {
+                return false;
+            }
+            pos += len;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reinterp::ThompsonInterpreter;
+    use testutil::{all_matches, compile, compile_mode, RULES, TEXT};
+
+    fn rules_dfa() -> Dfa {
+        DfaBuilder::new().build(&compile(RULES)).unwrap()
+    }
+
+    /**
+     * Put the checksum right after editing the table.
+     */
+    fn resum(bytes: &mut [u8]) {
+        let body_len = bytes.len() - 4;
+        let sum = checksum(&bytes[..body_len]);
+        bytes[body_len..].copy_from_slice(&sum.to_le_bytes());
+    }
+
+    /**
+     * Where to_bytes() writes the class count.
+     */
+    fn class_count_offset(dfa: &Dfa) -> usize {
+        let names: usize = dfa.conditions.iter().map(|name| 4 + name.len()).sum();
+        5 * 4 + names + 4 + 12 * dfa.trailing.len() + 4 + 8 * dfa.alphabet.starts.len()
+    }
+
+    fn dfa_matches(dfa: &Dfa) -> Vec<Vec<(usize, usize)>> {
+        all_matches(&mut DfaInterpreter::new(dfa.clone(), vec![]), TEXT)
+    }
+
+    #[test]
+    fn agrees_with_nfa() {
+        let mut nfa = ThompsonInterpreter::new(compile(RULES), vec![]);
+        assert_eq!(dfa_matches(&rules_dfa()), all_matches(&mut nfa, TEXT));
+    }
+
+    #[test]
+    fn round_trip() {
+        let dfa = rules_dfa();
+        let loaded = Dfa::from_bytes(&dfa.to_bytes()).unwrap();
+        assert_eq!(loaded.state_count(), dfa.state_count());
+        assert_eq!(loaded.class_count(), dfa.class_count());
+        assert_eq!(loaded.rule_count(), dfa.rule_count());
+        assert_eq!(dfa_matches(&loaded), dfa_matches(&dfa));
+        assert_eq!(loaded.to_bytes(), dfa.to_bytes());
+
+        let mut read = &dfa.to_bytes()[..];
+        let loaded = Dfa::read_from(&mut read).unwrap();
+        assert_eq!(dfa_matches(&loaded), dfa_matches(&dfa));
+    }
+
+    #[test]
+    fn round_trip_bytes() {
+        let rules: Vec<&str> = RULES.iter().cloned().filter(|rule| !rule.contains(r"\b")).collect();
+        let dfa = DfaBuilder::new().build(&compile_mode(&rules, true)).unwrap();
+        assert!(dfa.byte_mode());
+        let loaded = Dfa::from_bytes(&dfa.to_bytes()).unwrap();
+        assert_eq!(dfa_matches(&loaded), dfa_matches(&dfa));
+    }
+
+    #[test]
+    fn bad_tables() {
+        let bytes = rules_dfa().to_bytes();
+
+        let mut bad = bytes.clone();
+        bad[0] = b'X';
+        assert!(matches!(Dfa::from_bytes(&bad), Err(DfaError::BadMagic)));
+
+        let mut bad = bytes.clone();
+        bad[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        resum(&mut bad);
+        assert!(matches!(Dfa::from_bytes(&bad),
+                         Err(DfaError::UnsupportedVersion(v)) if v == FORMAT_VERSION + 1));
+
+        let mut bad = bytes.clone();
+        let middle = bad.len() / 2;
+        bad[middle] ^= 1;
+        assert!(matches!(Dfa::from_bytes(&bad), Err(DfaError::BadChecksum)));
+
+        assert!(matches!(Dfa::from_bytes(&bytes[..6]), Err(DfaError::Truncated)));
+        assert!(matches!(Dfa::from_bytes(&bytes[..bytes.len() - 1]), Err(DfaError::BadChecksum)));
+
+        // Cut short, but with a checksum that matches what is left.
+        let mut bad = bytes[..bytes.len() - 8].to_vec();
+        bad.extend_from_slice(&[0; 4]);
+        resum(&mut bad);
+        assert!(matches!(Dfa::from_bytes(&bad), Err(DfaError::Truncated)));
+
+        let mut bad = bytes.clone();
+        bad.truncate(bad.len() - 4);
+        bad.extend_from_slice(&[0; 8]);
+        resum(&mut bad);
+        assert!(matches!(Dfa::from_bytes(&bad), Err(DfaError::Corrupt(_))));
+    }
+
+    #[test]
+    fn minimized_agrees() {
+        let dfa = rules_dfa();
+        let mut min = dfa.clone();
+        let stats = min.minimize();
+        assert_eq!(stats.after, min.state_count());
+        assert!(min.state_count() <= dfa.state_count());
+        assert_eq!(dfa_matches(&min), dfa_matches(&dfa));
+    }
+
+    #[test]
+    fn huge_class_count_is_an_error() {
+        let dfa = rules_dfa();
+        let mut bytes = dfa.to_bytes();
+        let at = class_count_offset(&dfa);
+        let stored = TableReader { bytes: &bytes, pos: at }.usize().unwrap();
+        assert_eq!(stored, dfa.class_count());
+        bytes[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        resum(&mut bytes);
+        assert!(matches!(Dfa::from_bytes(&bytes), Err(DfaError::Corrupt(_))));
+    }
+
+
+    #[test]
+    fn byte_classes_tell_apart_only_what_the_rules_do() {
+        let prog = compile_mode(&["[b-d]+", "x", "é"], true);
+        let classes = prog.byte_classes();
+        assert_eq!(prog.stats().byte_classes, Some(classes.count()));
+        assert!(classes.count() < 20, "{} classes", classes.count());
+        assert_eq!(classes.get(b'b'), classes.get(b'd'));
+        assert_ne!(classes.get(b'a'), classes.get(b'b'));
+        assert_ne!(classes.get(b'x'), classes.get(b'y'));
+        // é is C3 A9.
+        assert_ne!(classes.get(0xC3), classes.get(0xC4));
+        assert_ne!(classes.get(0xA9), classes.get(0xAA));
+        assert_eq!(classes.get(0xC4), classes.get(0xFF));
+
+        let dfa = DfaBuilder::new().build(&prog).unwrap();
+        assert_eq!(dfa.class_count(), classes.count());
+        let text = "abcdxyé ddêé";
+        let mut nfa = ThompsonInterpreter::new(prog, vec![]);
+        assert_eq!(all_matches(&mut DfaInterpreter::new(dfa, vec![]), text), all_matches(&mut nfa, text));
+    }
+}
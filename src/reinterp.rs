@@ -1,6 +1,7 @@
-/**
+/*
  * Thompson style "breadth first" NFA interpreter.
- * Add dynamic programming, and you get a "just in time" DFA compiler.
+ * Add dynamic programming, and you get a "just in time" DFA compiler
+ * (see relazy.rs).
  *
  * Multiple patterns:
  * Append all the programs? Each one has 1 start instruction and 1 match.
@@ -10,254 +11,1290 @@
  */
 
 
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::cmp::Reverse;
+use std::iter::FromIterator;
 use std::mem::swap;
 use reprog::*;
 use sparse::SparseSet; // cribbed from regex crate, and from its ancestors
 use reprog::Instruction::*;
 use util::char_at;
+use reterm::Assertion;
+use rebytes;
+use rebytes::{InvalidUtf8, Utf8Error, INVALID_BYTE};
+use rescan::{NoActions, Progress, Scanner};
+use retoken::TokenizeError;
 
 
 
-struct TaskList {
-    t: SparseSet,
+/**
+ * A match of `rule`, `len` bytes long. For a trailing context rule r/s,
+ * `len` covers r, and `lookahead` is the length of the text s matched
+ * after it, which the token does not include. Matches are still ranked
+ * by their whole length, as in lex.
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct MatchRecord {
+    pub len: usize,
+    pub rule: usize,
+    pub lookahead: usize,
 }
 
-impl TaskList {
-    pub fn new(len: usize) -> TaskList {
-        TaskList { t: SparseSet::new(len) }
+impl MatchRecord {
+    pub fn new(p: usize, r: usize) -> MatchRecord {
+        MatchRecord { len: p, rule: r, lookahead: 0 }
+    }
+
+    /**
+     * The length of everything matched, trailing context included.
+     */
+    pub fn total_len(&self) -> usize {
+        self.len + self.lookahead
+    }
+
+    /**
+     * Split a match of a trailing context rule, where self.len is the
+     * length of the whole match, into the token and its lookahead.
+     */
+    pub fn with_token_len(&self, len: usize) -> MatchRecord {
+        MatchRecord { len, rule: self.rule, lookahead: self.total_len() - len }
     }
+}
+
+
+/**
+ * A set of rule ids, from MatchEngine::matches_set().
+ */
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RuleSet {
+    rules: Vec<usize>,      // sorted, no duplicates
+}
 
-    pub fn clear(&mut self) {
-        self.t.clear();
+impl RuleSet {
+    pub fn contains(&self, rule: usize) -> bool {
+        self.rules.binary_search(&rule).is_ok()
     }
 
     pub fn len(&self) -> usize {
-        self.t.len()
+        self.rules.len()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.t.is_empty()
+        self.rules.is_empty()
+    }
+
+    /**
+     * The rules, in order.
+     */
+    pub fn as_slice(&self) -> &[usize] {
+        &self.rules
+    }
+}
+
+impl FromIterator<usize> for RuleSet {
+    fn from_iter<I: IntoIterator<Item = usize>>(iter: I) -> RuleSet {
+        let mut rules: Vec<usize> = iter.into_iter().collect();
+        rules.sort();
+        rules.dedup();
+        RuleSet { rules }
+    }
+}
+
+
+
+pub type TokenizerAction = fn(&str) -> ();
+
+
+/**
+ * The part of a tokenizer that actually looks at the text: given a
+ * position, find every rule that matches there, and how far. The
+ * Thompson interpreter and the lazy DFA both do this, so code written
+ * against this trait can use either one.
+ */
+pub trait MatchEngine {
+
+    /**
+     * Find every match starting at &text[start..], replacing the
+     * results of any earlier call. Match lengths are relative to start.
+     */
+    fn all_matches_at(&mut self, text: &str, start: usize) {
+        self.all_matches_at_decoded(text, start, &[])
     }
 
-    pub fn add_task(&mut self, pc: Label) {
-        //println!("Adding task with pc = {}", pc);
-        if !self.t.contains(pc) {
-            self.t.insert(pc);
+    /**
+     * all_matches_at() for text decoded from bytes under
+     * InvalidUtf8::Token (see rebytes.rs). Each (start, end) in
+     * `invalid`, in order, is an invalid sequence, which only '\I'
+     * matches, whatever the text has there.
+     */
+    fn all_matches_at_decoded(&mut self, text: &str, start: usize, invalid: &[(usize, usize)]);
+
+    /**
+     * The matches found by the last call to all_matches_at().
+     */
+    fn matches(&self) -> &[MatchRecord];
+
+    /**
+     * Whether the last call to all_matches_at() got to the end of the
+     * text with some rule still in the running. If so, more text could
+     * have given different matches, which matters when the text is
+     * only the part of the input read so far (see restream.rs).
+     */
+    fn hit_end(&self) -> bool;
+
+    fn action(&self, rule: usize) -> TokenizerAction;
+
+    /**
+     * Only the rules active in this start condition (see
+     * reprog::StartCondition) will be tried from now on. Every engine
+     * starts out in reprog::INITIAL.
+     *
+     * An engine only holds the current condition. A stack of them, to
+     * push and pop as flex's yy_push_state() and yy_pop_state() do, is
+     * kept by retoken::Tokenizer.
+     *
+     * Fails, leaving the condition as it was, if the program has no
+     * condition `sc`.
+     */
+    fn set_start_condition(&mut self, sc: usize) -> Result<(), ConditionError>;
+
+    /**
+     * Longest match wins; among equally long matches, the lowest rule.
+     * Trailing context counts towards the length. Matches that would
+     * make an empty token don't count: this is the first of
+     * ranked_matches(), without ranking the rest.
+     */
+    fn best_match(&self) -> Option<MatchRecord> {
+        let mut best: Option<MatchRecord> = None;
+        for m in self.matches().iter().filter(|m| m.len > 0) {
+            match best {
+                Some(b) if m.total_len() < b.total_len() ||
+                           (m.total_len() == b.total_len() && m.rule >= b.rule) => {}
+                _ => { best = Some(*m); }
+            }
+        }
+        best
+    }
+
+    /**
+     * All the non-empty matches, best first by the same measure as
+     * best_match().
+     */
+    fn ranked_matches(&self) -> Vec<MatchRecord> {
+        let mut ranked: Vec<MatchRecord> = self.matches().iter()
+            .filter(|m| m.len > 0)
+            .cloned()
+            .collect();
+        ranked.sort_by(|a, b| b.total_len().cmp(&a.total_len()).then(a.rule.cmp(&b.rule)));
+        ranked.dedup_by(|a, b| a.total_len() == b.total_len() && a.rule == b.rule);
+        ranked
+    }
+
+    /**
+     * The rules that match all of text, e.g. to check a field against
+     * many patterns at once. This is all_matches_at() from the start,
+     * keeping the matches that reach the end. For a trailing context
+     * rule r/s, r/s as a whole has to match.
+     */
+    fn matches_set(&mut self, text: &str) -> RuleSet {
+        self.all_matches_at(text, 0);
+        self.matches().iter()
+            .filter(|m| m.total_len() == text.len())
+            .map(|m| m.rule)
+            .collect()
+    }
+
+    /**
+     * Whether any rule matches all of text, i.e. whether matches_set()
+     * would be non-empty. The engines in this crate override this to
+     * stop at the first rule that matches, without recording matches
+     * or splitting off trailing context.
+     */
+    fn is_match_any(&mut self, text: &str) -> bool {
+        self.all_matches_at(text, 0);
+        self.matches().iter().any(|m| m.total_len() == text.len())
+    }
+
+    /**
+     * Tokenize text lazily: see Tokens. (For a `dyn MatchEngine`, use
+     * Tokens::new() instead.)
+     */
+    fn tokens<'e, 't>(&'e mut self, text: &'t str) -> Tokens<'e, 't, Self>
+        where Self: Sized
+    {
+        Tokens::new(self, text)
+    }
+
+    /**
+     * Run the matching rule's action on each token in turn. This is
+     * just a loop over the tokens. Text that no rule matches is passed
+     * over, a character at a time.
+     */
+    fn apply(&mut self, text: &str) {
+        let mut tokens = Tokens::new(self, text);
+        while let Some(tok) = tokens.next() {
+            if !tok.is_unmatched() {
+                tokens.engine().action(tok.rule)(tok.text);
+            }
         }
     }
+
+    /**
+     * apply() for bytes that may not be valid UTF-8. Invalid sequences
+     * that no rule matches are passed over like unmatched text, unless
+     * `invalid` is Strict, in which case nothing is done and we fail.
+     */
+    fn apply_bytes(&mut self, bytes: &[u8], invalid: InvalidUtf8) -> Result<(), Utf8Error> {
+        let input = rebytes::decode(bytes, invalid)?;
+        let mut tokens = Tokens::new(self, &input.text).with_invalid(&input.invalid);
+        while let Some(tok) = tokens.next() {
+            if !tok.is_unmatched() && !tok.is_invalid() {
+                tokens.engine().action(tok.rule)(tok.text);
+            }
+        }
+        Ok(())
+    }
 }
 
 
-#[derive(Debug, Clone, Copy)]
-pub struct MatchRecord {
-    pub len: usize,
+impl<E: MatchEngine + ?Sized> MatchEngine for Box<E> {
+    fn all_matches_at(&mut self, text: &str, start: usize) {
+        (**self).all_matches_at(text, start)
+    }
+
+    fn all_matches_at_decoded(&mut self, text: &str, start: usize, invalid: &[(usize, usize)]) {
+        (**self).all_matches_at_decoded(text, start, invalid)
+    }
+
+    fn matches(&self) -> &[MatchRecord] {
+        (**self).matches()
+    }
+
+    fn hit_end(&self) -> bool {
+        (**self).hit_end()
+    }
+
+    fn action(&self, rule: usize) -> TokenizerAction {
+        (**self).action(rule)
+    }
+
+    fn set_start_condition(&mut self, sc: usize) -> Result<(), ConditionError> {
+        (**self).set_start_condition(sc)
+    }
+
+    fn is_match_any(&mut self, text: &str) -> bool {
+        (**self).is_match_any(text)
+    }
+}
+
+
+/**
+ * A piece of the text matched by a rule. start and end are byte
+ * offsets into the whole text, so text is &whole[start..end].
+ *
+ * When tokenizing bytes under InvalidUtf8::Token, a rule can match
+ * invalid UTF-8 with '\I'. The text has a placeholder (SUB, U+001A)
+ * for each byte of it, which is no different from a real SUB in the
+ * input. `invalid` says which of them are placeholders: it has the
+ * (start, end) byte offsets of each invalid sequence in the token, in
+ * the same terms as start and end. lossy_text() puts U+FFFD in their
+ * place. INVALID tokens (and all other tokens) have none.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'t> {
     pub rule: usize,
+    pub start: usize,
+    pub end: usize,
+    pub text: &'t str,
+    pub invalid: &'t [(usize, usize)],
 }
 
-impl MatchRecord {
-    pub fn new(p: usize, r: usize) -> MatchRecord {
-        MatchRecord { len: p, rule: r }
+/**
+ * The rule id of tokens made of text that no rule matches.
+ */
+pub const UNMATCHED: usize = usize::MAX;
+
+/**
+ * The rule id of tokens made of invalid UTF-8 (see rebytes.rs).
+ */
+pub const INVALID: usize = usize::MAX - 1;
+
+impl<'t> Token<'t> {
+    /**
+     * The token for &text[start..end], where text starts at byte offset
+     * `base` of the whole input.
+     */
+    pub(crate) fn at(rule: usize, text: &'t str, base: usize, start: usize, end: usize) -> Token<'t> {
+        Token { rule, start: base + start, end: base + end, text: &text[start..end], invalid: &[] }
+    }
+
+    /**
+     * The text, with each invalid sequence in it replaced by one U+FFFD,
+     * as String::from_utf8_lossy() would have it.
+     */
+    pub fn lossy_text(&self) -> Cow<'t, str> {
+        if self.invalid.is_empty() {
+            return Cow::Borrowed(self.text);
+        }
+        let mut text = String::with_capacity(self.text.len());
+        let mut pos = 0;
+        for &(start, end) in self.invalid {
+            text.push_str(&self.text[pos..start - self.start]);
+            text.push(char::REPLACEMENT_CHARACTER);
+            pos = end - self.start;
+        }
+        text.push_str(&self.text[pos..]);
+        Cow::Owned(text)
+    }
+
+    pub fn is_unmatched(&self) -> bool {
+        self.rule == UNMATCHED
+    }
+
+    pub fn is_invalid(&self) -> bool {
+        self.rule == INVALID
     }
 }
 
 
+/**
+ * What a tokenizer does with text that no rule matches (or where the
+ * only matches are empty, which would get us nowhere).
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnmatchedPolicy {
+    /// Pass over one character, as a token of its own (see UNMATCHED).
+    #[default]
+    SkipChar,
+    /// Pass over the whole run of characters up to where some rule
+    /// matches again, as a single token.
+    Coalesce,
+    /// Stop, and report the position as an error.
+    Stop,
+}
 
-pub type TokenizerAction = fn(&str) -> ();
+/**
+ * No rule matches the text at byte offset `pos`, and the policy was
+ * UnmatchedPolicy::Stop.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoMatchError {
+    pub pos: usize,
+}
+
+impl fmt::Display for NoMatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no rule matches the text at position {}", self.pos)
+    }
+}
+
+impl Error for NoMatchError {}
+
+/**
+ * There is no start condition `condition` in the program.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConditionError {
+    pub condition: usize,
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "no start condition {}", self.condition)
+    }
+}
+
+impl Error for ConditionError {}
+
+/**
+ * The position just past the character at pos.
+ */
+pub(crate) fn next_char(text: &str, pos: usize) -> usize {
+    pos + char_at(&text[pos..]).map_or(1, |(_, len)| len)
+}
+
+
+/**
+ * Iterator over the tokens of a text, from MatchEngine::tokens().
+ * Each call to next() finds the best match at the current position,
+ * and moves past it. Text that no rule matches is dealt with according
+ * to the UnmatchedPolicy: by default, each character of it comes out
+ * as an UNMATCHED token. With UnmatchedPolicy::Stop, iteration ends
+ * there, and error() says where. An invalid sequence that no rule
+ * matches is an INVALID token, whatever the policy.
+ */
+pub struct Tokens<'e, 't, E: 'e + MatchEngine + ?Sized> {
+    engine: &'e mut E,
+    scanner: Scanner<'t>,
+}
+
+impl<'e, 't, E: 'e + MatchEngine + ?Sized> Tokens<'e, 't, E> {
+    pub fn new(engine: &'e mut E, text: &'t str) -> Tokens<'e, 't, E> {
+        Tokens { engine, scanner: Scanner::new(text) }
+    }
+
+    pub fn with_unmatched_policy(mut self, policy: UnmatchedPolicy) -> Tokens<'e, 't, E> {
+        self.scanner.set_policy(policy);
+        self
+    }
+
+    /**
+     * The invalid sequences in the text (see rebytes::decode()).
+     */
+    pub(crate) fn with_invalid(mut self, invalid: &'t [(usize, usize)]) -> Tokens<'e, 't, E> {
+        self.scanner.set_invalid(invalid);
+        self
+    }
+
+    /**
+     * Carry on from where another Tokens over the same text got to.
+     */
+    pub(crate) fn resume(mut self, progress: Progress) -> Tokens<'e, 't, E> {
+        self.scanner.resume(progress);
+        self
+    }
+
+    pub(crate) fn progress(&self) -> Progress {
+        self.scanner.progress()
+    }
+
+    pub fn engine(&self) -> &E {
+        self.engine
+    }
+
+    /**
+     * Where we stopped, if we stopped at text no rule matches.
+     */
+    pub fn error(&self) -> Option<NoMatchError> {
+        match self.scanner.error() {
+            Some(TokenizeError::NoMatch(e)) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl<'e, 't, E: 'e + MatchEngine + ?Sized> Iterator for Tokens<'e, 't, E> {
+    type Item = Token<'t>;
+
+    fn next(&mut self) -> Option<Token<'t>> {
+        self.scanner.next(self.engine, &mut NoActions)
+    }
+}
 
 
 pub struct ThompsonInterpreter {
     pub matches: Vec<MatchRecord>, // string positions where matches ended
     prog: Program,
     actions: Vec<TokenizerAction>,
+    starts: Vec<Vec<Label>>,       // entry points for each start condition
+    condition: usize,
+    hit_end: bool,
 }
 
 impl ThompsonInterpreter {
     
     pub fn new(p: Program, acts: Vec<TokenizerAction>) -> ThompsonInterpreter {
+        let starts = (0..p.conditions.len()).map(|sc| p.condition_starts(sc)).collect();
         ThompsonInterpreter {
             matches: vec![],
             prog: p,
             actions: acts,
+            starts,
+            condition: INITIAL,
+            hit_end: false,
         }
     }
 
-    fn best_match(&self) -> Option<MatchRecord> {
-        if self.matches.is_empty() {
-            return None;
-        }
-        let mut best = MatchRecord {len: 0, rule: 0};
-        for m in &self.matches {
-            if m.len > best.len {
-                best = m.clone();
-            } else if m.len == best.len {
-                if m.rule < best.rule {
-                    best = m.clone();
-                }
-            }
-            // else m.len < best.len, and we continue
-        }
-        Some(best)
-    }
-
-    /**
-     * Loop through clist. Epsilon transitions (Split) add new entries to clist,
-     * so this implements epsilon-closure. All other instructions add new 
-     * entries to nlist.
-     * So this will apply all character tests to the current character, and
-     * return when it is done.
-     * There is no direct notion of failure here. If nothing is added to nlist,
-     * then the whole procedure will terminate. There is a global notion of
-     * failure which can be checked then, namely were there any matches. 
-     */
-    fn advance(
-        &mut self, 
-        str_pos: usize, 
-        ch: char, 
-        clist: &mut TaskList, 
-        nlist: &mut TaskList
-    ) {
-        //println!("advance: '{}'", ch);
-        let mut i: usize = 0;
-        loop {
-            if i >= clist.len() {
-                //println!("finished with clist, end of match advance");
-                return; // really we want to break out of the outer loop here...
-            }
+    /**
+     * The leftmost-longest match in the text at or after byte offset
+     * `start`, as a token: the first place a token could start, and the
+     * token Tokens would pick there. Empty matches do not count.
+     *
+     * Rather than trying all_matches_at() at each place in turn, this
+     * makes a single pass, adding the entry points as new threads at
+     * each position, until something matches. Each thread remembers
+     * where it started, and where threads meet, the one that started
+     * first is the one to keep. Except that a thread that went on to
+     * the trailing context s of r/s without r matching anything is
+     * only going to find an empty token, so it comes last.
+     */
+    pub fn find_at<'t>(&mut self, text: &'t str, start: usize) -> Option<Token<'t>> {
+        let plen = self.prog.len();
+        let mut clist = MarkedTasks::new(plen);
+        let mut nlist = MarkedTasks::new(plen);
+        let mut leftmost = Leftmost { prog: &self.prog, text, pos: start, best: None };
 
-            let pc = clist.t.at(i);
-            i += 1;
-
-            //println!("Executing instruction at line {}", pc);
-            let inst = &self.prog[pc];
-            match *inst {
-                Char(ref data) => {
-                    if data.ch == ch {
-                        //println!("Matched '{}' at string pos {}", data.ch, str_pos);
-                        //println!("Add task to nlist at {}", pc + 1);
-                        nlist.add_task(data.goto);
-                    } else if data.nocase {
-                        if data.ch.to_lowercase().collect::<String>() == 
-                           ch.to_lowercase().collect::<String>() {
-                            //println!("i-Matched '{}' at string pos {}", data.ch, str_pos);
-                            nlist.add_task(data.goto);
-                        }
-                    }
-                    // otherwise the thread dies here
-                }
-                AnyChar(ref data) => {
-                    nlist.add_task(data.goto);
-                }
-                CharClass(ref ccd) => {
-                    if ccd.data.matches(ch) {
-                        //println!("CharClass {} matches {} at {}", ccd.data, ch, str_pos);
-                        nlist.add_task(ccd.goto);
-                    } else if ccd.nocase {
-                        if ccd.data.matches(ch.to_lowercase().next().unwrap()) {
-                            //println!("CharClass {} i-matches {} at {}", ccd.data, ch, str_pos);
-                            nlist.add_task(ccd.goto);
-                        }
-                    }
-                }
-                Match(ref data) => {
-                    //println!("Match: {} [{}]", str_pos, data.rule_id);
-                    self.matches.push(MatchRecord::new(str_pos, data.rule_id));
-                }
-                Split(l1, l2) => {
-                    //println!("Task at {} added to clist", l1);
-                    clist.add_task(l1);
-                    //println!("Task at {} added to clist", l2);
-                    clist.add_task(l2);
+        let mut at = Cursor::new(text, start, text.len(), self.prog.byte_mode(), &[]);
+        loop {
+            leftmost.pos = at.pos;
+            if leftmost.best.is_none() && text.is_char_boundary(at.pos) {
+                for &entry in &self.starts[self.condition] {
+                    clist.add(entry, (true, Reverse(at.pos)));
                 }
             }
+            nfa_step(&self.prog, &at, &mut leftmost, &mut clist, &mut nlist);
+            if (nlist.is_empty() && leftmost.best.is_some()) || !at.step() {
+                break;
+            }
+            swap(&mut clist, &mut nlist);
+            nlist.clear();
         }
+        leftmost.best.map(|(from, m)| Token::at(m.rule, text, 0, from, from + m.len))
+    }
 
+    /**
+     * The leftmost-longest match in the text; see find_at().
+     */
+    pub fn find<'t>(&mut self, text: &'t str) -> Option<Token<'t>> {
+        self.find_at(text, 0)
     }
 
+    /**
+     * Iterator over the non-overlapping leftmost-longest matches in the
+     * text, as for grep -o.
+     */
+    pub fn find_iter<'e, 't>(&'e mut self, text: &'t str) -> FindIter<'e, 't> {
+        FindIter { engine: self, text, pos: 0 }
+    }
+}
 
+/**
+ * Iterator over matches, from ThompsonInterpreter::find_iter(). Each
+ * search starts where the last match ended.
+ */
+pub struct FindIter<'e, 't> {
+    engine: &'e mut ThompsonInterpreter,
+    text: &'t str,
+    pos: usize,
+}
+
+impl<'e, 't> Iterator for FindIter<'e, 't> {
+    type Item = Token<'t>;
+
+    fn next(&mut self) -> Option<Token<'t>> {
+        let tok = self.engine.find_at(self.text, self.pos)?;
+        self.pos = tok.end;
+        Some(tok)
+    }
+}
+
+impl MatchEngine for ThompsonInterpreter {
 
     /**
-     * Find a token starting at &text[begin..], if possible.
+     * Find a token starting at &text[start..], if possible.
+     * We get the whole text rather than just the remainder, since
+     * assertions like '^' and '\b' need to see what came before.
      * Results are stored in self.matches, and so "failure" is indicated
-     * by an empty match list.
+     * by an empty match list. Match lengths are relative to start.
      */
-    fn all_matches_at(&mut self, text: &str) {
+    fn all_matches_at_decoded(&mut self, text: &str, start: usize, invalid: &[(usize, usize)]) {
 
         let plen = self.prog.len();
-        let mut clist = TaskList::new(plen);
-        let mut nlist = TaskList::new(plen);
+        let mut clist = MarkedTasks::new(plen);
+        let mut nlist = MarkedTasks::new(plen);
 
         self.matches.clear();
+        self.hit_end = false;
 
-        for start in &self.prog.starts {
+        for start in &self.starts[self.condition] {
             //println!(">> Adding entry point {} to clist", *start);
-            clist.add_task(*start);
+            clist.add(*start, ());
         }
-        let mut pos = 0;
-        let mut nxt = 0;
-        let mut ch: char;
-        while !clist.is_empty() {
 
-            pos += nxt;
+        let mut at = Cursor::new(text, start, text.len(), self.prog.byte_mode(), invalid);
+        while !clist.is_empty() {
 
-            match char_at(&text[pos..]) {
-                None => { 
-                    if pos == text.len() {
-                        ch = 0 as char;
-                    } else {
-                        panic!("ERROR: Could not decode character at {}", pos);
-                    }
-                }
-                Some((c, byte_len)) => {
-                    nxt = byte_len;
-                    ch = c;
-                    //println!("pos: {}; nxt: {}; ch: '{}'", pos, nxt, ch);
-                }
+            let mut all = AllMatches { matches: &mut self.matches, len: at.pos - start };
+            nfa_step(&self.prog, &at, &mut all, &mut clist, &mut nlist);
+            if !at.step() {
+                // Only here to collect the matches; there is nothing
+                // left for surviving threads to consume.
+                self.hit_end = true;
+                break;
             }
-
-            self.advance(pos, ch, &mut clist, &mut nlist);
             
             // rebind clist and nlist
             swap(&mut clist, &mut nlist);
             nlist.clear();
         }
+
+        if self.prog.has_trailing() {
+            split_trailing_context(&self.prog, text, start, invalid, &mut self.matches);
+        }
+    }
+
+    fn matches(&self) -> &[MatchRecord] {
+        &self.matches
+    }
+
+    fn hit_end(&self) -> bool {
+        self.hit_end
+    }
+
+    fn action(&self, rule: usize) -> TokenizerAction {
+        self.actions[rule]
+    }
+
+    fn set_start_condition(&mut self, sc: usize) -> Result<(), ConditionError> {
+        if sc >= self.starts.len() {
+            return Err(ConditionError { condition: sc });
+        }
+        self.condition = sc;
+        Ok(())
     }
 
     /**
-     * Loops over the characters in the input, but will exit early if
-     * we ever reach a point where nothing in the input matches.
-     * Then clist will be empty.
-     * Currently, we have no way of knowing what caused termination
-     * (out of string? no surviving threads?). It is just a matter of
-     * whether there were any matches at that point.
-     * 
-     * This is not quite correct. There should be an outer loop which 
-     * consumes the string, and an inner loop which finds matches.
-     * When we are done looking for matches (clist is empty), we bump
-     * our string position to either the end of the best match (if there
-     * were any) or one position forward (if there were no matches).
-     *
-     * That latter is assuming that we do not require a match of some kind
-     * on every character. Otherwise we have to fail harder in cases where
-     * the match list comes back empty.
-     */
-    pub fn apply(&mut self, text: &str) {
-
-        let mut pos: usize = 0;
-        while pos < text.len() {
-            self.all_matches_at(&text[pos..]);
-            // Now, what is our best match, if any? 
-            match self.best_match() {
-                None => {
-                    // increment pos by 1 and try again
-                    println!("No rule matched at pos {}", pos);
-                    pos += 1;
+     * all_matches_at() from the start, but stopping at the first match
+     * at the end of the text. Leaves matches() alone.
+     */
+    fn is_match_any(&mut self, text: &str) -> bool {
+        let plen = self.prog.len();
+        let mut clist = MarkedTasks::new(plen);
+        let mut nlist = MarkedTasks::new(plen);
+        for start in &self.starts[self.condition] {
+            clist.add(*start, ());
+        }
+        let mut at = Cursor::new(text, 0, text.len(), self.prog.byte_mode(), &[]);
+        while !clist.is_empty() {
+            let mut any = AnyAtEnd { at_end: at.pos == at.end };
+            if nfa_step(&self.prog, &at, &mut any, &mut clist, &mut nlist) == Some(true) {
+                return true;
+            }
+            if !at.step() {
+                break;
+            }
+            swap(&mut clist, &mut nlist);
+            nlist.clear();
+        }
+        false
+    }
+}
+
+
+/**
+ * The matches of trailing context rules r/s come out of the engines
+ * with the length of the whole match. Cut them back to the length of r,
+ * moving the rest into the lookahead.
+ *
+ * Where r or s has a fixed length, that settles it. Otherwise we run
+ * the rule again from `start` (see trailing_mark()), to find the
+ * longest r that leaves the rest for s. `invalid` is as for
+ * MatchEngine::all_matches_at_decoded().
+ */
+pub(crate) fn split_trailing_context(
+    prog: &Program,
+    text: &str,
+    start: usize,
+    invalid: &[(usize, usize)],
+    matches: &mut [MatchRecord],
+) {
+    for m in matches.iter_mut() {
+        if let Some((entry, info)) = prog.trailing(m.rule) {
+            let whole = &text[start..start + m.len];
+            let len = match info.fixed_split(whole) {
+                Some(len) => len,
+                None => trailing_mark(prog, entry, text, start, m.len, invalid),
+            };
+            *m = m.with_token_len(len);
+        }
+    }
+}
+
+/**
+ * Run one rule's code (starting at `entry`) over &text[start..start +
+ * full_len], keeping track, for each thread, of where it passed the Mark
+ * between r and s. Threads that meet at an instruction have the same
+ * future, so we only need to keep the latest mark. Returns the mark
+ * of the latest thread to match all of full_len.
+ */
+fn trailing_mark(
+    prog: &Program,
+    entry: Label,
+    text: &str,
+    start: usize,
+    full_len: usize,
+    invalid: &[(usize, usize)],
+) -> usize {
+    let mut clist = MarkedTasks::new(prog.len());
+    let mut nlist = MarkedTasks::new(prog.len());
+    let mut latest = LatestMark { start, pos: start, full_len, best: None };
+
+    clist.add(entry, None);
+    let mut at = Cursor::new(text, start, start + full_len, prog.byte_mode(), invalid);
+    loop {
+        latest.pos = at.pos;
+        nfa_step(prog, &at, &mut latest, &mut clist, &mut nlist);
+        if nlist.is_empty() || !at.step() {
+            break;
+        }
+        swap(&mut clist, &mut nlist);
+        nlist.clear();
+    }
+    latest.best.unwrap_or(full_len)
+}
+
+/**
+ * What the NFA can see at a position: what there is to consume, and
+ * whether assertions hold. A Cursor sees the text itself; the lazy DFA
+ * (see relazy::step()) only knows what kind of character came before.
+ */
+pub(crate) trait Here {
+    /**
+     * The character to consume, in character mode.
+     */
+    fn ch(&self) -> Option<char>;
+
+    /**
+     * The byte to consume, in byte mode.
+     */
+    fn byte(&self) -> Option<u8>;
+
+    /**
+     * Whether there is an invalid sequence to consume.
+     */
+    fn at_invalid(&self) -> bool;
+
+    /**
+     * Whether the assertion holds here, or None if we can not tell.
+     */
+    fn holds(&self, kind: Assertion) -> Option<bool>;
+}
+/**
+ * A position in the text, as the NFA sees it. Only &text[..end] is
+ * consumed, but assertions at `end` still see what comes after.
+ *
+ * In byte mode, the NFA consumes a byte at a time, and the characters
+ * around the position are only decoded when an assertion asks for
+ * them (which only happens between characters, since the byte code for
+ * a character is all or nothing). Otherwise it consumes characters, and
+ * we keep track of the ones on either side as we go.
+ *
+ * In either mode, an invalid sequence (see
+ * MatchEngine::all_matches_at_decoded()) is consumed all at once, and
+ * only by '\I'.
+ */
+struct Cursor<'t> {
+    text: &'t str,
+    pos: usize,
+    end: usize,
+    byte_mode: bool,
+    prev: Option<char>,             // character mode only
+    next: Option<(char, usize)>,    // character mode only, and ignoring end
+    invalid: &'t [(usize, usize)],  // the invalid sequences from pos on
+}
+
+impl<'t> Cursor<'t> {
+    fn new(
+        text: &'t str,
+        pos: usize,
+        end: usize,
+        byte_mode: bool,
+        invalid: &'t [(usize, usize)],
+    ) -> Cursor<'t> {
+        let invalid = &invalid[invalid.partition_point(|&(_, e)| e <= pos)..];
+        let mut at = Cursor { text, pos, end, byte_mode, prev: None, next: None, invalid };
+        if !byte_mode {
+            at.prev = text[..pos].chars().next_back();
+            at.next = char_at(&text[pos..]);
+        }
+        at
+    }
+
+    /**
+     * Move past the character, byte or invalid sequence we just
+     * consumed. False if we were already at the end.
+     */
+    fn step(&mut self) -> bool {
+        if self.pos >= self.end {
+            return false;
+        }
+        if self.at_invalid() {
+            self.pos = self.invalid[0].1;
+            self.invalid = &self.invalid[1..];
+            if !self.byte_mode {
+                self.prev = Some(INVALID_BYTE);
+                self.next = char_at(&self.text[self.pos..]);
+            }
+        } else if self.byte_mode {
+            self.pos += 1;
+        } else if let Some((c, len)) = self.next {
+            self.pos += len;
+            self.prev = Some(c);
+            self.next = char_at(&self.text[self.pos..]);
+        }
+        true
+    }
+}
+
+impl<'t> Here for Cursor<'t> {
+    fn ch(&self) -> Option<char> {
+        if self.pos < self.end && !self.at_invalid() { self.next.map(|(c, _)| c) } else { None }
+    }
+
+    fn byte(&self) -> Option<u8> {
+        if self.pos < self.end && !self.at_invalid() {
+            Some(self.text.as_bytes()[self.pos])
+        } else {
+            None
+        }
+    }
+
+    fn at_invalid(&self) -> bool {
+        self.pos < self.end && self.invalid.first().is_some_and(|&(s, _)| s == self.pos)
+    }
+
+    fn holds(&self, kind: Assertion) -> Option<bool> {
+        let (prev, next) = if self.byte_mode {
+            (self.text[..self.pos].chars().next_back(), char_at(&self.text[self.pos..]).map(|(c, _)| c))
+        } else {
+            (self.prev, self.next.map(|(c, _)| c))
+        };
+        Some(kind.holds(prev, next))
+    }
+}
+
+/**
+ * A task list where each task carries a mark (see Threads). Adding a
+ * task that is already there with a greater mark updates the mark, and
+ * puts the task back on the agenda, so that the greater mark gets
+ * passed on to wherever it leads. With () for marks, this is a plain
+ * task list.
+ */
+pub(crate) struct MarkedTasks<M> {
+    present: SparseSet,
+    marks: Vec<M>,
+    agenda: Vec<Label>,
+}
+
+impl<M: Copy + Ord + Default> MarkedTasks<M> {
+    pub(crate) fn new(len: usize) -> MarkedTasks<M> {
+        MarkedTasks {
+            present: SparseSet::new(len),
+            marks: vec![M::default(); len],
+            agenda: vec![],
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.present.clear();
+        self.agenda.clear();
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.present.is_empty()
+    }
+
+    pub(crate) fn add(&mut self, pc: Label, mark: M) {
+        if !self.present.contains(pc) {
+            self.present.insert(pc);
+            self.marks[pc] = mark;
+            self.agenda.push(pc);
+        } else if mark > self.marks[pc] {
+            self.marks[pc] = mark;
+            self.agenda.push(pc);
+        }
+    }
+
+    /**
+     * The tasks, in the order they were first added.
+     */
+    pub(crate) fn tasks(&self) -> Vec<Label> {
+        (0..self.present.len()).map(|i| self.present.at(i)).collect()
+    }
+}
+
+
+/**
+ * What a run of the NFA makes of its threads. Each thread carries a
+ * mark, and where two threads meet at an instruction, they have the
+ * same future, so only the one with the greater mark is kept. That is
+ * how find_at() prefers the thread that started first, and
+ * trailing_mark() the one that passed the Mark of r/s last.
+ */
+pub(crate) trait Threads {
+    type Mark: Copy + Ord + Default;
+
+    /**
+     * Whether a thread with this mark is still worth running.
+     */
+    fn wanted(&self, _mark: Self::Mark) -> bool {
+        true
+    }
+
+    /**
+     * The mark of a thread once it has passed a Mark instruction.
+     */
+    fn passed_mark(&self, mark: Self::Mark) -> Self::Mark {
+        mark
+    }
+
+    /**
+     * A thread with this mark reached the Match instruction of `rule`.
+     * True to stop there.
+     */
+    fn matched(&mut self, rule: usize, mark: Self::Mark) -> bool;
+}
+
+/**
+ * Run the threads in clist on what is `here`. Epsilon transitions
+ * (Split, Mark, and assertions that hold) add new entries to clist, so
+ * this implements epsilon-closure. All other instructions add new
+ * entries to nlist. So this will apply all character tests to the
+ * current character, and return when it is done.
+ *
+ * There is no direct notion of failure here. If nothing is added to
+ * nlist, then the whole procedure will terminate.
+ *
+ * Returns None if `here` can not tell whether an assertion holds, and
+ * otherwise whether `threads` asked to stop at a match.
+ */
+pub(crate) fn nfa_step<H: Here, T: Threads>(
+    prog: &Program,
+    here: &H,
+    threads: &mut T,
+    clist: &mut MarkedTasks<T::Mark>,
+    nlist: &mut MarkedTasks<T::Mark>,
+) -> Option<bool> {
+    let ch = here.ch();
+    while let Some(pc) = clist.agenda.pop() {
+        let mark = clist.marks[pc];
+        if !threads.wanted(mark) {
+            continue;
+        }
+        match prog[pc] {
+            Char(ref data) => {
+                if ch == Some(data.ch) {
+                    nlist.add(data.goto, mark);
+                }
+            }
+            AnyChar(ref data) => {
+                if ch.is_some_and(|c| c != '\n' || data.dot_nl) {
+                    nlist.add(data.goto, mark);
                 }
-                Some(mtch) => {
-                    // emit a token
-                    //println!("TOKEN: {} -> {} [{}]", pos, pos + mtch.len, mtch.rule);
-                    self.actions[mtch.rule](&text[pos..(pos + mtch.len)]);
-                    // increment pos by mtch length and continue
-                    pos += mtch.len;
+            }
+            CharClass(ref ccd) => {
+                if ch.is_some_and(|c| ccd.data.matches(c)) {
+                    nlist.add(ccd.goto, mark);
+                }
+            }
+            ByteRange(ref br) => {
+                if here.byte().is_some_and(|b| br.lo <= b && b <= br.hi) {
+                    nlist.add(br.goto, mark);
+                }
+            }
+            ByteSwitch(ref bs) => {
+                if let Some(goto) = here.byte().and_then(|b| bs.goto(b)) {
+                    nlist.add(goto, mark);
+                }
+            }
+            Assert(ref data) => {
+                if here.holds(data.kind)? {
+                    clist.add(data.goto, mark);
+                }
+            }
+            Invalid(l) => {
+                if here.at_invalid() {
+                    nlist.add(l, mark);
+                }
+            }
+            Mark(l) => {
+                clist.add(l, threads.passed_mark(mark));
+            }
+            Match(ref data) => {
+                if threads.matched(data.rule_id, mark) {
+                    return Some(true);
+                }
+            }
+            Split(l1, l2) => {
+                clist.add(l2, mark);
+                clist.add(l1, mark);
+            }
+        }
+    }
+    Some(false)
+}
+
+/**
+ * Threads for find_at(). Marks are whether the thread can still find a
+ * token, and where it started, reversed so that earlier is greater.
+ */
+struct Leftmost<'p, 't> {
+    prog: &'p Program,
+    text: &'t str,
+    pos: usize,
+    best: Option<(usize, MatchRecord)>,     // where it starts, and the match
+}
+
+impl<'p, 't> Threads for Leftmost<'p, 't> {
+    type Mark = (bool, Reverse<usize>);
+
+    fn wanted(&self, (_, Reverse(from)): Self::Mark) -> bool {
+        // Starting later than the best match, it can not be leftmost.
+        self.best.is_none_or(|(b, _)| from <= b)
+    }
+
+    fn passed_mark(&self, (_, from): Self::Mark) -> Self::Mark {
+        (self.pos > from.0, from)
+    }
+
+    fn matched(&mut self, rule: usize, (_, Reverse(from)): Self::Mark) -> bool {
+        let mut m = [MatchRecord::new(self.pos - from, rule)];
+        if self.prog.has_trailing() {
+            split_trailing_context(self.prog, self.text, from, &[], &mut m);
+        }
+        let better = match self.best {
+            None => true,
+            Some((b, ref bm)) => {
+                from < b || (from == b && (m[0].total_len() > bm.total_len() ||
+                    (m[0].total_len() == bm.total_len() && m[0].rule < bm.rule)))
+            }
+        };
+        if m[0].len > 0 && better {
+            self.best = Some((from, m[0]));
+        }
+        false
+    }
+}
+
+/**
+ * Threads for all_matches_at(): every match is recorded, `len` from
+ * where they started.
+ */
+struct AllMatches<'m> {
+    matches: &'m mut Vec<MatchRecord>,
+    len: usize,
+}
+
+impl<'m> Threads for AllMatches<'m> {
+    type Mark = ();
+
+    fn matched(&mut self, rule: usize, _mark: ()) -> bool {
+        self.matches.push(MatchRecord::new(self.len, rule));
+        false
+    }
+}
+
+/**
+ * Threads for is_match_any(): stop at the first match, if it is at the
+ * end of the text.
+ */
+struct AnyAtEnd {
+    at_end: bool,
+}
+
+impl Threads for AnyAtEnd {
+    type Mark = ();
+
+    fn matched(&mut self, _rule: usize, _mark: ()) -> bool {
+        self.at_end
+    }
+}
+
+/**
+ * Threads for trailing_mark(). Marks are how far from `start` the
+ * thread passed the Mark, if it has.
+ */
+struct LatestMark {
+    start: usize,
+    pos: usize,
+    full_len: usize,
+    best: Option<usize>,
+}
+
+impl Threads for LatestMark {
+    type Mark = Option<usize>;
+
+    fn passed_mark(&self, _mark: Option<usize>) -> Option<usize> {
+        Some(self.pos - self.start)
+    }
+
+    fn matched(&mut self, _rule: usize, mark: Option<usize>) -> bool {
+        if self.pos - self.start == self.full_len && mark > self.best {
+            self.best = mark;
+        }
+        false
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{compile, engines, match_lengths, tokens};
+
+    #[test]
+    fn text_and_line_anchors() {
+        let cases: &[(&str, &str, usize, &[usize])] = &[
+            ("^a", "aa", 0, &[1]),
+            ("^a", "aa", 1, &[]),
+            ("^a", "x\na", 2, &[]),
+            ("(?m)^a", "x\na", 2, &[1]),
+            ("(?m)^a", "xa", 1, &[]),
+            ("a$", "a", 0, &[1]),
+            ("a$", "ab", 0, &[]),
+            ("a$", "a\nb", 0, &[]),
+            ("(?m)a$", "a\nb", 0, &[1]),
+            (r"\Aa", "aa", 1, &[]),
+            (r"(?m)\Aa", "\na", 1, &[]),
+            (r"(?m)a\z", "a\n", 0, &[]),
+            (r"a\z", "ba", 1, &[1]),
+            ("^", "ab", 0, &[0]),
+            ("^$", "", 0, &[0]),
+        ];
+        for &(rule, text, start, want) in cases {
+            assert_eq!(match_lengths(rule, text, start), want, "{:?} on {:?} at {}", rule, text, start);
+        }
+    }
+
+    #[test]
+    fn word_boundaries() {
+        let cases: &[(&str, &str, usize, &[usize])] = &[
+            (r"\bfoo\b", "a foo b", 2, &[3]),
+            (r"\bfoo\b", "afoo", 1, &[]),
+            (r"\bfoo\b", "foo_", 0, &[]),
+            (r"a\Bb", "ab", 0, &[2]),
+            (r"a\bb", "ab", 0, &[]),
+            (r"a\b.", "a-", 0, &[2]),
+            (r"\B", " ", 0, &[0]),
+            // Non-ASCII word characters count too.
+            (r"x\b", "xé", 0, &[]),
+            (r"\bé", " é", 1, &[2]),
+            (r"\w+\b", "naïve!", 0, &[6]),
+        ];
+        for &(rule, text, start, want) in cases {
+            assert_eq!(match_lengths(rule, text, start), want, "{:?} on {:?} at {}", rule, text, start);
+        }
+    }
+
+    #[test]
+    fn longest_match_wins_then_lowest_rule() {
+        let rules = ["if", "[a-z]+", "[a-z]+/=", r"\s+", "x*", "ab|abc"];
+        let want = vec![
+            (1, "iffy"), (3, " "),
+            (0, "if"), (3, " "),
+            // The trailing context counts, so r/s beats a longer r.
+            (2, "ab"), (UNMATCHED, "="), (3, " "),
+            // "x*" only matches the empty string here, which is no token.
+            (UNMATCHED, "-"),
+            (1, "abc"),
+        ];
+        for mut engine in engines(&rules) {
+            assert_eq!(tokens(&mut engine, "iffy if ab= -abc"), want);
+        }
+    }
+
+    #[test]
+    fn best_match_heads_ranked_matches() {
+        for mut engine in engines(&["a", "ab", "a", "abc/d", "[a-z]+"]) {
+            engine.all_matches_at("abcd", 0);
+            let ranked: Vec<_> = engine.ranked_matches().iter()
+                .map(|m| (m.rule, m.len, m.lookahead))
+                .collect();
+            assert_eq!(ranked, [(3, 3, 1), (4, 4, 0), (4, 3, 0), (1, 2, 0), (4, 2, 0),
+                                (0, 1, 0), (2, 1, 0), (4, 1, 0)]);
+            let best = engine.best_match().unwrap();
+            assert_eq!((best.rule, best.len), (3, 3));
+        }
+    }
+
+
+    #[test]
+    fn slash_is_trailing_context() {
+        for mut engine in engines(&["a/b", "b", "."]) {
+            assert_eq!(tokens(&mut engine, "abac"), vec![(0, "a"), (1, "b"), (2, "a"), (2, "c")]);
+        }
+        // Escaped, or in brackets, it is just a slash.
+        for rule in &[r"a\/b", "a[/]b"] {
+            for mut engine in engines(&[rule, "."]) {
+                assert_eq!(tokens(&mut engine, "a/bab"),
+                           vec![(0, "a/b"), (1, "a"), (1, "b")]);
+            }
+        }
+    }
+
+    #[test]
+    fn trailing_context_splits_where_r_ends() {
+        // A fixed length r or s settles where the token ends; when both
+        // vary, the NFA finds the longest r that leaves the rest to s.
+        let cases: &[(&str, &str, &str)] = &[
+            ("do/n't", "don't", "do"),
+            (r"ab/\d+", "ab123", "ab"),
+            ("[a-z]+/x", "abcx", "abc"),
+            ("a+/a*b", "aaab", "aaa"),
+            (r"[a-z0-9]+/[a-z]*\d", "abc1d2", "abc1d"),
+        ];
+        for &(rule, text, token) in cases {
+            for mut engine in engines(&[rule]) {
+                engine.all_matches_at(text, 0);
+                let m = engine.best_match().unwrap();
+                assert_eq!((m.len, m.total_len()), (token.len(), text.len()), "{:?} on {:?}", rule, text);
+            }
+        }
+    }
+
+
+    #[test]
+    fn find_is_leftmost_then_longest() {
+        // "bcdef" is longer, but "ab" starts first; where both start at
+        // the same place, the longest wins, as it would for tokens.
+        let rules = ["ab", "bcdef", "x+y?", "[a-z]+/!"];
+        let text = "abcdef xxxy abc!";
+        let mut nfa = ThompsonInterpreter::new(compile(&rules), vec![]);
+        let found: Vec<_> = nfa.find_iter(text).map(|t| (t.rule, t.start, t.text)).collect();
+        assert_eq!(found, vec![(0, 0, "ab"), (2, 7, "xxxy"), (3, 12, "abc")]);
+        let tok = nfa.find_at(text, 1).unwrap();
+        assert_eq!((tok.rule, tok.start, tok.text), (1, 1, "bcdef"));
+        assert!(nfa.find_at(text, 15).is_none());
+
+        // Going through the text in order, a token is the longest match
+        // where the last one ended, which for find would be "bcdef".
+        for mut engine in engines(&rules) {
+            assert_eq!(&tokens(&mut engine, "abcdef")[..2], &[(0, "ab"), (UNMATCHED, "c")]);
+        }
+    }
+
+
+    #[test]
+    fn matches_set_is_the_rules_that_match_all_of_the_text() {
+        let rules = ["[a-z]+", "[a-z]+[0-9]*", "abc", "ab", "[a-z]+/[0-9]", r"\w+\b", "x"];
+        let cases: &[(&str, &[usize])] = &[
+            ("abc", &[0, 1, 2, 5]),
+            ("ab", &[0, 1, 3, 5]),
+            // r/s has to match as a whole.
+            ("abc1", &[1, 4, 5]),
+            ("1", &[5]),
+            ("é1", &[5]),
+            ("abc!", &[]),
+            ("", &[]),
+        ];
+        for mut engine in engines(&rules) {
+            for &(text, want) in cases {
+                assert_eq!(engine.matches_set(text).as_slice(), want, "{:?}", text);
+
+                // is_match_any() agrees, and does not touch the matches.
+                engine.all_matches_at("abc1", 0);
+                let before = engine.matches().len();
+                assert_eq!(engine.is_match_any(text), !want.is_empty(), "{:?}", text);
+                assert_eq!(engine.matches().len(), before, "{:?}", text);
+            }
+        }
+    }
+
+    #[test]
+    fn is_match_any_agrees_with_matches_set() {
+        // Each engine stops early in its own way: at the first match at
+        // the end of the text, at a dead state, or (the lazy DFA in byte
+        // mode, at a '\b' next to "é") by handing over to the NFA.
+        let rules = [r"\bé+\b", "(a|aa)*b?", "a*/b", r"[^\n]*\d"];
+        let text = "aaab é1\nab";
+        for mut engine in engines(&rules) {
+            for (end, _) in text.char_indices().chain(Some((text.len(), ' '))) {
+                for (start, _) in text[..end].char_indices() {
+                    let part = &text[start..end];
+                    let any = !engine.matches_set(part).is_empty();
+                    assert_eq!(engine.is_match_any(part), any, "{:?}", part);
                 }
             }
         }
@@ -0,0 +1,604 @@
+/*
+ * Lazy ("just in time") DFA.
+ *
+ * This is the Thompson interpreter plus dynamic programming. A DFA state
+ * is the set of NFA threads that are alive at some position, and the
+ * transition out of it on a given character is worked out by running
+ * the NFA one step, exactly as the Thompson interpreter would (see
+ * reinterp::nfa_step()). The difference is that we remember the answer,
+ * so the next time we are in the same set of threads looking at the
+ * same character, it is just a table lookup.
+ *
+ * Two wrinkles:
+ *
+ * Assertions depend on the characters on either side of the current
+ * position. The next character is the one we are making a transition
+ * on, so that is fine. For the previous character, each state records
+ * what kind of character got us there (see Context), and that is part
+ * of its identity.
+ *
+ * An invalid UTF-8 sequence (see rebytes.rs) is an input of its own,
+ * with a transition of its own, which only '\I' makes.
+ *
+ * Matches are found during the epsilon closure, which happens when we
+ * leave a state. So the rules that matched at a position are stored in
+ * the state we reach after consuming the character at that position.
+ * That is, match information is delayed by one step, as in RE2. The
+ * end of the text gets its own transition, so that the matches there
+ * can be found the same way.
+ *
+ * The cache of states is bounded. When it fills up, we throw all of it
+ * away and start again from the current state.
+ *
+ * Programs compiled in byte mode (see Program::byte_mode()) make
+ * transitions on bytes instead of characters, with a table column per
+ * byte class (Program::byte_classes()) rather than per byte. The state
+ * after a non-ASCII byte does not know which character it was part of,
+ * which '\b' and '\B' need to know. Rather than multiply the states to
+ * keep track, we give up when that happens (Input::next_char(), and the
+ * NonAscii context), and let the NFA find the matches instead.
+ */
+
+use std::collections::HashMap;
+use reprog::*;
+use reinterp::{ConditionError, MatchEngine, MatchRecord, TokenizerAction, ThompsonInterpreter};
+use reinterp::{nfa_step, split_trailing_context, Here, MarkedTasks, Threads};
+use rebytes::INVALID_BYTE;
+use reterm::{Assertion, NamedClass};
+use util::char_at;
+
+
+/**
+ * Default maximum number of cached DFA states.
+ */
+pub const DEFAULT_CACHE_LIMIT: usize = 10_000;
+
+type StateId = usize;
+
+/**
+ * Not a real state id. Marks transitions that have not been computed.
+ */
+const UNKNOWN: StateId = usize::MAX;
+
+/**
+ * Not a real state id either. Marks transitions the DFA can not make,
+ * so the NFA has to take over (byte mode only).
+ */
+const QUIT: StateId = usize::MAX - 1;
+
+/**
+ * What we need to know about the character before a position in order
+ * to evaluate assertions there.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Context {
+    Start,
+    Newline,
+    Word,
+    Other,
+    NonAscii,   // byte mode: somewhere in or after a non-ASCII character
+}
+
+impl Context {
+    pub(crate) const ALL: [Context; 4] =
+        [Context::Start, Context::Newline, Context::Word, Context::Other];
+
+    pub(crate) fn of(prev: Option<char>) -> Context {
+        match prev {
+            None => Context::Start,
+            Some('\n') => Context::Newline,
+            Some(c) if NamedClass::Word.matches(c) => Context::Word,
+            Some(_) => Context::Other,
+        }
+    }
+
+    /**
+     * A character that Assertion::holds() will treat the same way as
+     * any character of this kind. NonAscii characters could be word
+     * characters or not, so that only goes for assertions that do not
+     * care.
+     */
+    fn representative(&self) -> Option<char> {
+        match *self {
+            Context::Start => None,
+            Context::Newline => Some('\n'),
+            Context::Word => Some('a'),
+            Context::Other => Some(' '),
+            Context::NonAscii => Some('\u{80}'),
+        }
+    }
+}
+
+
+/**
+ * What a transition is made on: a character, or in byte mode a byte,
+ * or an invalid sequence, or the end of the text.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Input {
+    Char(char),
+    Byte(u8),
+    Invalid,
+    End,
+}
+
+impl Input {
+    /**
+     * The character starting here, as far as assertions can tell. For a
+     * non-ASCII byte, that is some non-ASCII character, as for
+     * Context::NonAscii.
+     */
+    fn next_char(&self) -> Option<char> {
+        match *self {
+            Input::Char(c) => Some(c),
+            Input::Byte(b) if b < 0x80 => Some(b as char),
+            Input::Byte(_) => Some('\u{80}'),
+            Input::Invalid => Some(INVALID_BYTE),
+            Input::End => None,
+        }
+    }
+
+    /**
+     * Whether next_char() is only a stand-in for the real character.
+     */
+    fn is_vague(&self) -> bool {
+        match *self {
+            Input::Byte(b) => b >= 0x80,
+            _ => false,
+        }
+    }
+
+    fn context_after(&self, before: Context) -> Context {
+        match *self {
+            Input::End => before,
+            Input::Byte(b) if b >= 0x80 => Context::NonAscii,
+            _ => Context::of(self.next_char()),
+        }
+    }
+}
+
+/**
+ * The input at byte offset `pos` of the text, a character or a byte,
+ * and how many bytes it takes up. If the first of `invalid` (as for
+ * MatchEngine::all_matches_at_decoded()) starts here, it is that
+ * instead.
+ */
+pub(crate) fn input_at(
+    text: &str,
+    pos: usize,
+    byte_mode: bool,
+    invalid: &[(usize, usize)],
+) -> (Input, usize) {
+    if let Some(&(start, end)) = invalid.first().filter(|&&(start, _)| start == pos) {
+        (Input::Invalid, end - start)
+    } else if byte_mode {
+        match text.as_bytes().get(pos) {
+            Some(&b) => (Input::Byte(b), 1),
+            None => (Input::End, 0),
+        }
+    } else {
+        match char_at(&text[pos..]) {
+            Some((c, len)) => (Input::Char(c), len),
+            None => (Input::End, 0),
+        }
+    }
+}
+
+
+/**
+ * The identity of a DFA state: the NFA threads waiting to consume the
+ * next character, the kind of character that brought us here, and the
+ * rules that matched just before that character. The thread and rule
+ * lists are sorted, so equal sets have equal keys.
+ */
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct StateKey {
+    pub(crate) threads: Vec<Label>,
+    pub(crate) context: Context,
+    pub(crate) matches: Vec<usize>,
+}
+
+impl StateKey {
+    /**
+     * The state we are in before looking at any text: the entry points
+     * of the rules in some start condition (see
+     * Program::condition_starts()), and the given preceding context.
+     */
+    pub(crate) fn start(starts: &[Label], context: Context) -> StateKey {
+        StateKey { threads: starts.to_vec(), context, matches: vec![] }
+    }
+}
+
+struct DfaState {
+    key: StateKey,
+    table: Vec<StateId>,            // ASCII characters, or byte classes
+    other: HashMap<char, StateId>,  // other characters
+    invalid: StateId,               // transition on an invalid sequence
+    eot: StateId,                   // transition at the end of the text
+}
+
+impl DfaState {
+    fn new(key: StateKey, table_len: usize) -> DfaState {
+        DfaState {
+            key,
+            table: vec![UNKNOWN; table_len],
+            other: HashMap::new(),
+            invalid: UNKNOWN,
+            eot: UNKNOWN,
+        }
+    }
+
+    fn is_dead(&self) -> bool {
+        self.key.threads.is_empty()
+    }
+
+    fn transition(&self, input: Input, classes: &ByteClasses) -> StateId {
+        match input {
+            Input::End => self.eot,
+            Input::Invalid => self.invalid,
+            Input::Byte(b) => self.table[classes.get(b)],
+            Input::Char(c) if (c as u32) < 128 => self.table[c as usize],
+            Input::Char(c) => *self.other.get(&c).unwrap_or(&UNKNOWN),
+        }
+    }
+
+    fn set_transition(&mut self, input: Input, classes: &ByteClasses, to: StateId) {
+        match input {
+            Input::End => { self.eot = to; }
+            Input::Invalid => { self.invalid = to; }
+            Input::Byte(b) => { self.table[classes.get(b)] = to; }
+            Input::Char(c) if (c as u32) < 128 => { self.table[c as usize] = to; }
+            Input::Char(c) => { self.other.insert(c, to); }
+        }
+    }
+}
+
+
+pub struct LazyDfa {
+    pub matches: Vec<MatchRecord>,
+    prog: Program,
+    byte_classes: ByteClasses,  // byte mode: columns of the tables
+    actions: Vec<TokenizerAction>,
+    states: Vec<DfaState>,
+    index: HashMap<StateKey, StateId>,
+    cache_limit: usize,
+    flushes: usize,
+    scratch: Scratch,       // for step()
+    starts: Vec<Vec<Label>>,    // entry points for each start condition
+    condition: usize,
+    hit_end: bool,
+    fallback: Option<ThompsonInterpreter>,  // for when we QUIT
+}
+
+impl LazyDfa {
+
+    pub fn new(p: Program, acts: Vec<TokenizerAction>) -> LazyDfa {
+        let plen = p.len();
+        let starts = (0..p.conditions.len()).map(|sc| p.condition_starts(sc)).collect();
+        LazyDfa {
+            matches: vec![],
+            byte_classes: p.byte_classes(),
+            prog: p,
+            actions: acts,
+            states: vec![],
+            index: HashMap::new(),
+            cache_limit: DEFAULT_CACHE_LIMIT,
+            flushes: 0,
+            scratch: Scratch::new(plen),
+            starts,
+            condition: INITIAL,
+            hit_end: false,
+            fallback: None,
+        }
+    }
+
+    /**
+     * Set the maximum number of DFA states to keep around. The cache is
+     * cleared whenever it would grow past this (but it always has room
+     * for at least the current state and the next one).
+     */
+    pub fn set_cache_limit(&mut self, limit: usize) {
+        self.cache_limit = ::std::cmp::max(limit, 2);
+    }
+
+    /**
+     * The number of states currently cached.
+     */
+    pub fn state_count(&self) -> usize {
+        self.states.len()
+    }
+
+    /**
+     * How many times the cache has been cleared because it was full.
+     */
+    pub fn cache_flushes(&self) -> usize {
+        self.flushes
+    }
+
+    fn start_state(&mut self, context: Context) -> StateId {
+        let key = StateKey::start(&self.starts[self.condition], context);
+        self.add_state(key)
+    }
+
+    fn add_state(&mut self, key: StateKey) -> StateId {
+        if let Some(&id) = self.index.get(&key) {
+            return id;
+        }
+        let id = self.states.len();
+        self.index.insert(key.clone(), id);
+        let table_len = if self.prog.byte_mode() { self.byte_classes.count() } else { 128 };
+        self.states.push(DfaState::new(key, table_len));
+        id
+    }
+
+    /**
+     * Follow the transition out of `from` on `input`, computing it if it
+     * is not cached yet. If the cache has to be flushed to make room,
+     * `from` is re-added first, so that the new transition has somewhere
+     * to live. Returns QUIT if the DFA can not tell where to go.
+     */
+    fn next_state(&mut self, from: StateId, input: Input) -> StateId {
+        let to = self.states[from].transition(input, &self.byte_classes);
+        if to != UNKNOWN {
+            return to;
+        }
+        let key = match step(&self.prog, &mut self.scratch, &self.states[from].key, input) {
+            Some(key) => key,
+            None => {
+                self.states[from].set_transition(input, &self.byte_classes, QUIT);
+                return QUIT;
+            }
+        };
+        let mut from = from;
+        if !self.index.contains_key(&key) && self.states.len() >= self.cache_limit {
+            let current = self.states[from].key.clone();
+            self.states.clear();
+            self.index.clear();
+            self.flushes += 1;
+            from = self.add_state(current);
+        }
+        let to = self.add_state(key);
+        self.states[from].set_transition(input, &self.byte_classes, to);
+        to
+    }
+
+    /**
+     * The NFA to fall back on, in the current start condition.
+     */
+    fn nfa(&mut self) -> &mut ThompsonInterpreter {
+        let prog = &self.prog;
+        let nfa = self.fallback.get_or_insert_with(|| ThompsonInterpreter::new(prog.clone(), vec![]));
+        nfa.set_start_condition(self.condition).expect("the NFA has the same conditions");
+        nfa
+    }
+
+    /**
+     * Find the matches with the NFA instead.
+     */
+    fn nfa_matches_at(&mut self, text: &str, start: usize, invalid: &[(usize, usize)]) {
+        self.nfa().all_matches_at_decoded(text, start, invalid);
+        let nfa = self.fallback.as_ref().unwrap();
+        self.matches.clear();
+        self.matches.extend_from_slice(nfa.matches());
+        self.hit_end = nfa.hit_end();
+    }
+}
+
+/**
+ * Scratch space for step(), sized for the program.
+ */
+pub(crate) struct Scratch {
+    clist: MarkedTasks<()>,
+    nlist: MarkedTasks<()>,
+}
+
+impl Scratch {
+    pub(crate) fn new(plen: usize) -> Scratch {
+        Scratch { clist: MarkedTasks::new(plen), nlist: MarkedTasks::new(plen) }
+    }
+}
+
+/**
+ * What the NFA sees in a state, on `input`: the character before, only
+ * as its Context.
+ */
+struct Seen {
+    context: Context,
+    input: Input,
+}
+
+impl Here for Seen {
+    fn ch(&self) -> Option<char> {
+        match self.input {
+            Input::Char(c) => Some(c),
+            _ => None,
+        }
+    }
+
+    fn byte(&self) -> Option<u8> {
+        match self.input {
+            Input::Byte(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    fn at_invalid(&self) -> bool {
+        self.input == Input::Invalid
+    }
+
+    fn holds(&self, kind: Assertion) -> Option<bool> {
+        if kind.is_word_boundary() && (self.context == Context::NonAscii || self.input.is_vague()) {
+            return None;
+        }
+        Some(kind.holds(self.context.representative(), self.input.next_char()))
+    }
+}
+
+/**
+ * Threads for step(): which rules matched.
+ */
+struct Rules(Vec<usize>);
+
+impl Threads for Rules {
+    type Mark = ();
+
+    fn matched(&mut self, rule: usize, _mark: ()) -> bool {
+        self.0.push(rule);
+        false
+    }
+}
+
+/**
+ * Run the NFA one step from the threads in `key`, on `input`. This is
+ * reinterp::nfa_step(), as the Thompson interpreter does it, but
+ * collecting the results into the key for the next state.
+ * Returns None if a '\b' or '\B' needs to know about a non-ASCII
+ * character we only have a byte of.
+ */
+pub(crate) fn step(prog: &Program, scratch: &mut Scratch, key: &StateKey, input: Input) -> Option<StateKey> {
+    let Scratch { ref mut clist, ref mut nlist } = *scratch;
+    clist.clear();
+    nlist.clear();
+    for &pc in key.threads.iter().rev() {
+        clist.add(pc, ());
+    }
+    let mut matches = Rules(vec![]);
+    nfa_step(prog, &Seen { context: key.context, input }, &mut matches, clist, nlist)?;
+
+    let mut threads = nlist.tasks();
+    threads.sort();
+    let mut matches = matches.0;
+    matches.sort();
+    matches.dedup();
+    let context = input.context_after(key.context);
+    if input == Input::End {
+        threads.clear();
+    }
+    Some(StateKey { threads, context, matches })
+}
+
+impl MatchEngine for LazyDfa {
+
+    fn all_matches_at_decoded(&mut self, text: &str, start: usize, invalid: &[(usize, usize)]) {
+        self.matches.clear();
+
+        let context = Context::of(text[..start].chars().next_back());
+        let mut state = self.start_state(context);
+        let byte_mode = self.prog.byte_mode();
+        let mut pos = start;
+        let mut ahead = &invalid[invalid.partition_point(|&(_, end)| end <= start)..];
+        loop {
+            let (input, len) = input_at(text, pos, byte_mode, ahead);
+            state = self.next_state(state, input);
+            if state == QUIT {
+                self.nfa_matches_at(text, start, invalid);
+                return;
+            }
+
+            // The state we just reached knows which rules matched before
+            // the character that took us there, i.e. at pos.
+            let dfa_state = &self.states[state];
+            for &rule in &dfa_state.key.matches {
+                self.matches.push(MatchRecord::new(pos - start, rule));
+            }
+            if input == Input::End || dfa_state.is_dead() {
+                self.hit_end = input == Input::End;
+                break;
+            }
+            if input == Input::Invalid {
+                ahead = &ahead[1..];
+            }
+            pos += len;
+        }
+
+        if self.prog.has_trailing() {
+            split_trailing_context(&self.prog, text, start, invalid, &mut self.matches);
+        }
+    }
+
+    fn matches(&self) -> &[MatchRecord] {
+        &self.matches
+    }
+
+    fn hit_end(&self) -> bool {
+        self.hit_end
+    }
+
+    fn action(&self, rule: usize) -> TokenizerAction {
+        self.actions[rule]
+    }
+
+    fn set_start_condition(&mut self, sc: usize) -> Result<(), ConditionError> {
+        if sc >= self.starts.len() {
+            return Err(ConditionError { condition: sc });
+        }
+        self.condition = sc;
+        Ok(())
+    }
+
+    /**
+     * Only the state at the end of the text matters, so this just runs
+     * the DFA there, giving up at a dead state. Leaves matches() alone.
+     */
+    fn is_match_any(&mut self, text: &str) -> bool {
+        let mut state = self.start_state(Context::of(None));
+        let byte_mode = self.prog.byte_mode();
+        let mut pos = 0;
+        loop {
+            let (input, len) = input_at(text, pos, byte_mode, &[]);
+            state = self.next_state(state, input);
+            if state == QUIT {
+                return self.nfa().is_match_any(text);
+            }
+            let dfa_state = &self.states[state];
+            if input == Input::End {
+                return !dfa_state.key.matches.is_empty();
+            }
+            if dfa_state.is_dead() {
+                return false;
+            }
+            pos += len;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::{all_matches, compile, compile_mode, RULES, TEXT};
+
+    #[test]
+    fn agrees_with_nfa() {
+        let want = all_matches(&mut ThompsonInterpreter::new(compile(RULES), vec![]), TEXT);
+        for &byte_mode in &[false, true] {
+            let mut dfa = LazyDfa::new(compile_mode(RULES, byte_mode), vec![]);
+            assert_eq!(all_matches(&mut dfa, TEXT), want, "byte mode {}", byte_mode);
+            assert!(dfa.state_count() > 0);
+            assert_eq!(dfa.cache_flushes(), 0);
+        }
+    }
+
+    #[test]
+    fn small_cache_gives_same_matches() {
+        let want = all_matches(&mut ThompsonInterpreter::new(compile(RULES), vec![]), TEXT);
+        let mut dfa = LazyDfa::new(compile(RULES), vec![]);
+        dfa.set_cache_limit(4);
+        assert_eq!(all_matches(&mut dfa, TEXT), want);
+        assert!(dfa.cache_flushes() > 0);
+        assert!(dfa.state_count() <= 4);
+    }
+
+    #[test]
+    fn engines_behind_the_trait_agree() {
+        let engines: Vec<Box<dyn MatchEngine>> = vec![
+            Box::new(ThompsonInterpreter::new(compile(RULES), vec![])),
+            Box::new(LazyDfa::new(compile(RULES), vec![])),
+        ];
+        let found: Vec<_> = engines.into_iter().map(|mut engine| {
+            engine.all_matches_at(TEXT, 10);
+            (engine.best_match().map(|m| (m.rule, m.len)), engine.hit_end())
+        }).collect();
+        assert_eq!(found, [(Some((0, 2)), false), (Some((0, 2)), false)]);
+    }
+}
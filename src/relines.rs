@@ -0,0 +1,200 @@
+/*
+ * Line and column numbers for tokens.
+ *
+ * Tokens only know their byte offsets into the text. To say where they
+ * are in lines and columns, we count line breaks and characters from
+ * the start of the text. Tokens come out in order, so a LineTracker
+ * keeps its place and only ever counts the text between one token and
+ * the next.
+ *
+ * A line ends at "\n", "\r\n" or a lone "\r". Columns are counted in
+ * characters, and also in UTF-16 code units, which is what editors
+ * (and the Language Server Protocol) use. Lines and columns both start
+ * at 1.
+ */
+
+use reinterp::Token;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,          // in characters
+    pub utf16_column: usize,    // in UTF-16 code units
+}
+
+impl Position {
+    pub fn start() -> Position {
+        Position { line: 1, column: 1, utf16_column: 1 }
+    }
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self::start()
+    }
+}
+
+
+/**
+ * Finds the Position of byte offsets into a text. Offsets asked about
+ * in increasing order cost only the characters between them; going
+ * backwards means counting again from the start.
+ */
+#[derive(Debug, Clone)]
+pub struct LineTracker<'t> {
+    text: &'t str,
+    pos: usize,         // byte offset of `at`
+    at: Position,
+}
+
+impl<'t> LineTracker<'t> {
+    pub fn new(text: &'t str) -> LineTracker<'t> {
+        LineTracker { text, pos: 0, at: Position::start() }
+    }
+
+    /**
+     * The Position of the character starting at byte offset pos (or of
+     * the end of the text). pos must be on a character boundary.
+     */
+    pub fn position(&mut self, pos: usize) -> Position {
+        if pos < self.pos {
+            self.pos = 0;
+            self.at = Position::start();
+        }
+        for ch in self.text[self.pos..pos].chars() {
+            match ch {
+                // The "\n" of "\r\n" is not another line break.
+                '\n' if self.after_cr() => {}
+                '\n' | '\r' => {
+                    self.at.line += 1;
+                    self.at.column = 1;
+                    self.at.utf16_column = 1;
+                }
+                _ => {
+                    self.at.column += 1;
+                    self.at.utf16_column += ch.len_utf16();
+                }
+            }
+            self.pos += ch.len_utf8();
+        }
+        self.at
+    }
+
+    /**
+     * Whether the last character we counted was a "\r".
+     */
+    fn after_cr(&self) -> bool {
+        self.text[..self.pos].ends_with('\r')
+    }
+}
+
+
+/**
+ * A token, with the Positions of its first character and of the
+ * character just after it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocatedToken<'t> {
+    pub token: Token<'t>,
+    pub start: Position,
+    pub end: Position,
+}
+
+/**
+ * Iterator adapter that adds Positions to tokens of a text, as they
+ * come out of reinterp::Tokens or retoken::Scan. Build it with
+ * with_positions().
+ */
+pub struct WithPositions<'t, I> {
+    tokens: I,
+    lines: LineTracker<'t>,
+}
+
+/**
+ * Locate each of `tokens`, which must all be tokens of `text`.
+ */
+pub fn with_positions<'t, I>(text: &'t str, tokens: I) -> WithPositions<'t, I::IntoIter>
+    where I: IntoIterator<Item = Token<'t>>
+{
+    WithPositions { tokens: tokens.into_iter(), lines: LineTracker::new(text) }
+}
+
+impl<'t, I> WithPositions<'t, I> {
+    /**
+     * The underlying token iterator, e.g. to check its error().
+     */
+    pub fn tokens(&self) -> &I {
+        &self.tokens
+    }
+}
+
+impl<'t, I: Iterator<Item = Token<'t>>> Iterator for WithPositions<'t, I> {
+    type Item = LocatedToken<'t>;
+
+    fn next(&mut self) -> Option<LocatedToken<'t>> {
+        let token = self.tokens.next()?;
+        let start = self.lines.position(token.start);
+        let end = self.lines.position(token.end);
+        Some(LocatedToken { token, start, end })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reinterp::Tokens;
+    use relazy::LazyDfa;
+    use testutil::compile;
+
+    fn at(line: usize, column: usize, utf16_column: usize) -> Position {
+        Position { line, column, utf16_column }
+    }
+
+    #[test]
+    fn each_kind_of_line_break_counts_once() {
+        let text = "ab\ncd\r\nef\rgh\n\r\n";
+        let mut lines = LineTracker::new(text);
+        let want = [
+            (0, at(1, 1, 1)), (2, at(1, 3, 3)), (3, at(2, 1, 1)),
+            (5, at(2, 3, 3)), (6, at(3, 1, 1)), (7, at(3, 1, 1)),
+            (9, at(3, 3, 3)), (10, at(4, 1, 1)), (12, at(4, 3, 3)),
+            (13, at(5, 1, 1)), (14, at(6, 1, 1)), (15, at(6, 1, 1)),
+        ];
+        for &(pos, position) in &want {
+            assert_eq!(lines.position(pos), position, "at {}", pos);
+        }
+        // Going back counts again from the start.
+        assert_eq!(lines.position(3), at(2, 1, 1));
+        assert_eq!(lines.position(7), at(3, 1, 1));
+    }
+
+    #[test]
+    fn columns_count_characters_and_utf16_units() {
+        // 'é' is one UTF-16 unit, the emoji two.
+        let text = "é😀x\n😀😀y";
+        let mut lines = LineTracker::new(text);
+        assert_eq!(lines.position(2), at(1, 2, 2));
+        assert_eq!(lines.position(6), at(1, 3, 4));
+        assert_eq!(lines.position(7), at(1, 4, 5));
+        assert_eq!(lines.position(16), at(2, 3, 5));
+        assert_eq!(lines.position(text.len()), at(2, 4, 6));
+    }
+
+    #[test]
+    fn tokens_get_start_and_end_positions() {
+        let text = "ab 😀\r\ncd";
+        let mut engine = LazyDfa::new(compile(&[r"\w+", r"\s+"]), vec![]);
+        let found: Vec<_> = with_positions(text, Tokens::new(&mut engine, text))
+            .map(|tok| (tok.token.text, tok.start, tok.end))
+            .collect();
+        assert_eq!(found, vec![
+            ("ab", at(1, 1, 1), at(1, 3, 3)),
+            (" ", at(1, 3, 3), at(1, 4, 4)),
+            ("😀", at(1, 4, 4), at(1, 5, 6)),
+            ("\r\n", at(1, 5, 6), at(2, 1, 1)),
+            ("cd", at(2, 1, 1), at(2, 3, 3)),
+        ]);
+    }
+}
@@ -1,27 +1,151 @@
+use std::error::Error;
+use std::fmt;
 use reterm::*;
 
 
-struct ParseContext {
-    no_case: bool,
+/**
+ * The inline flags in effect at some point in the pattern.
+ * A group gets a copy of the flags of its context, and whatever
+ * it does to them is forgotten at the closing ')'.
+ */
+#[derive(Debug, Clone, Copy, Default)]
+struct Flags {
+    no_case: bool,      // i: case-insensitive
+    dot_nl: bool,       // s: '.' matches '\n' too
+    multi_line: bool,   // m: '^' and '$' match at line boundaries
+    extended: bool,     // x: ignore whitespace and '#' comments
+}
+
+struct ParseContext<'a> {
+    pattern: &'a str,
+    flags: Flags,
+}
+
+impl<'a> ParseContext<'a> {
+    pub fn new(pattern: &'a str) -> ParseContext<'a> {
+        ParseContext { pattern, flags: Flags::default(), }
+    }
+
+    /**
+     * Build an error pointing at the start of `at`, which must be a
+     * suffix of the pattern being parsed. The offending text is the
+     * first `len` bytes of `at` (clipped to what is actually there).
+     */
+    fn error(&self, kind: ParseErrorKind, at: &str, len: usize) -> ParseError {
+        let offset = self.pattern.len() - at.len();
+        let mut end = ::std::cmp::min(len, at.len());
+        while !at.is_char_boundary(end) {
+            end += 1;
+        }
+        ParseError {
+            kind,
+            offset,
+            text: at[..end].to_string(),
+            pattern: self.pattern.to_string(),
+        }
+    }
 }
 
-impl ParseContext {
-    pub fn new() -> ParseContext {
-        ParseContext { no_case: false, }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnbalancedParen,
+    DanglingBackslash,
+    UnterminatedClass,
+    EmptyClass,
+    BadFlag,
+    MissingFlag,
+    MissingOperand,
+    BadRepetition,
+    BadClassName,
+    BadClassRange,
+    BadEscape,
+    BadTrailingContext,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ParseErrorKind::*;
+        let msg = match *self {
+            UnbalancedParen => "unbalanced parenthesis",
+            DanglingBackslash => "pattern ends in a backslash",
+            UnterminatedClass => "unterminated character class",
+            EmptyClass => "empty character class",
+            BadFlag => "unrecognized flag",
+            MissingFlag => "missing flag",
+            MissingOperand => "missing operand",
+            BadRepetition => "invalid repetition bounds",
+            BadClassName => "unknown character class name",
+            BadClassRange => "invalid character class range",
+            BadEscape => "invalid escape sequence",
+            BadTrailingContext => "trailing context '/' is only allowed once, outside any group",
+        };
+        write!(f, "{}", msg)
     }
 }
 
-pub fn parse(text: &str) -> Term
+/**
+ * What went wrong, and where. `offset` is a byte offset into `pattern`,
+ * and `text` is the piece of the pattern that the parser choked on.
+ */
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub offset: usize,
+    pub text: String,
+    pub pattern: String,
+}
+
+/**
+ * Renders as a message, followed by the pattern with a caret under
+ * the offending position:
+ *
+ * ```text
+ * regex parse error: unbalanced parenthesis at offset 0: '('
+ *     (ab
+ *     ^
+ * ```
+ */
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "regex parse error: {} at offset {}: '{}'",
+                 self.kind, self.offset, self.text)?;
+        writeln!(f, "    {}", self.pattern)?;
+        let column = self.pattern[..self.offset].chars().count();
+        write!(f, "    {}^", " ".repeat(column))
+    }
+}
+
+impl Error for ParseError {}
+
+
+type ParseResult<'a> = Result<(Term, &'a str), ParseError>;
+
+/**
+ * <rule> ::= <regex> OR <regex> '/' <regex>
+ *
+ * The second form is lex's trailing context: 'r/s' matches r, but only
+ * where s follows it. Use '\/' for a literal slash.
+ */
+pub fn parse(text: &str) -> Result<Term, ParseError>
 {
-    let mut ctx = ParseContext::new();
-    match parse_regex(text, &mut ctx) {
-        Some((t, s)) => {
-            if !s.is_empty() {
-                println!("Did not parse the whole regex string. Remainder: '{}'", s);
-            }
-            t
-        },
-        None => panic!("Parse failed!")
+    let mut ctx = ParseContext::new(text);
+    let (t, rmdr) = parse_regex(text, &mut ctx)?;
+    let (t, rmdr) = match rmdr.strip_prefix('/') {
+        None => (t, rmdr),
+        Some(trail) => {
+            let (s, rmdr) = parse_regex(trail, &mut ctx)?;
+            (Term::new(TermType::TrailingContext, vec!(t, s)), rmdr)
+        }
+    };
+    if rmdr.starts_with(')') {
+        Err(ctx.error(ParseErrorKind::UnbalancedParen, rmdr, 1))
+    } else if rmdr.starts_with('/') {
+        Err(ctx.error(ParseErrorKind::BadTrailingContext, rmdr, 1))
+    } else {
+        // parse_regex only stops early at a ')' or a '/'.
+        debug_assert!(rmdr.is_empty());
+        Ok(t)
     }
 }
 
@@ -30,48 +154,37 @@ pub fn parse(text: &str) -> Term
  * <alt> ::= <conc> OR <conc> '|' <alt>
  * <conc> ::= <iter> OR <iter> <conc>
  * <iter> ::= <base> OR <iter> '*' OR <iter> '+' OR <iter> '?'
- * <base> ::= <char> OR '(' <regex> ')' OR '\' <char> OR '.'
+ *            OR <iter> '{' <n> '}' OR <iter> '{' <n> ',' '}'
+ *            OR <iter> '{' <n> ',' <m> '}'
+ * <base> ::= <char> OR '(' <regex> ')' OR '\' <escape> OR '.'
+ *            OR '^' OR '$' OR <flags> OR '(' '?' <flag-setting>* ':' <regex> ')'
  */
-fn parse_regex<'a>(text: &'a str, ctx: &mut ParseContext) -> Option<(Term, &'a str)>
+fn parse_regex<'a>(text: &'a str, ctx: &mut ParseContext<'a>) -> ParseResult<'a>
 {
     //println!("parse_regex '{}'", text);
     parse_alt(text, ctx)
 }
 
-fn parse_alt<'a>(text: &'a str, ctx: &mut ParseContext) -> Option<(Term, &'a str)> {
+fn parse_alt<'a>(text: &'a str, ctx: &mut ParseContext<'a>) -> ParseResult<'a> {
     //println!("parse_alt '{}'", text);
-    match parse_conc(text, ctx) {
-        None => None,
-        Some((t1, rmdr1)) => {
-            if !rmdr1.starts_with("|") {
-                Some((t1, rmdr1))
-            } else {
-                match parse_alt(&rmdr1[1..], ctx) {
-                    None => None,
-                    Some((t2, rmdr2)) => 
-                        Some((Term::new(TermType::Alternation, vec!(t1, t2)), rmdr2))
-                }
-            }
-        }
+    let (t1, rmdr1) = parse_conc(text, ctx)?;
+    if !rmdr1.starts_with('|') {
+        Ok((t1, rmdr1))
+    } else {
+        let (t2, rmdr2) = parse_alt(&rmdr1[1..], ctx)?;
+        Ok((Term::new(TermType::Alternation, vec!(t1, t2)), rmdr2))
     }
 }
 
-fn parse_conc<'a>(text: &'a str, ctx: &mut ParseContext) -> Option<(Term, &'a str)> {
+fn parse_conc<'a>(text: &'a str, ctx: &mut ParseContext<'a>) -> ParseResult<'a> {
     //println!("parse_conc '{}'", text);
-    match parse_iter(text, ctx) {
-        None => None,
-        Some((t1, rmdr1)) => {
-            if rmdr1.is_empty() || is_operator(rmdr1.chars().next().unwrap()) {
-                // 'is_operator' really means 'is_not_a_character_literal'
-                Some((t1, rmdr1))
-            } else {
-                match parse_conc(rmdr1, ctx) {
-                    None => None,
-                    Some((t2, rmdr2)) =>
-                        Some((Term::new(TermType::Concatenation, vec!(t1, t2)), rmdr2))
-                }
-            }
-        }
+    let (t1, rmdr1) = parse_iter(text, ctx)?;
+    if rmdr1.is_empty() || is_operator(rmdr1.chars().next().unwrap()) {
+        // 'is_operator' really means 'is_not_a_character_literal'
+        Ok((t1, rmdr1))
+    } else {
+        let (t2, rmdr2) = parse_conc(rmdr1, ctx)?;
+        Ok((Term::new(TermType::Concatenation, vec!(t1, t2)), rmdr2))
     }
 }
 
@@ -80,63 +193,192 @@ fn parse_conc<'a>(text: &'a str, ctx: &mut ParseContext) -> Option<(Term, &'a st
  *    <iter> -> <iter> '*'
  * But this is left-recursive.
  */
-fn parse_iter<'a>(text: &'a str, ctx: &mut ParseContext) -> Option<(Term, &'a str)> {
+fn parse_iter<'a>(text: &'a str, ctx: &mut ParseContext<'a>) -> ParseResult<'a> {
     //println!("parse_iter '{}'", text);
-    match parse_atom(text, ctx) {
-        None => None,
-        Some((mut t1, mut rmdr1)) => {
-            loop {
-                match rmdr1.chars().next() {
+    let (mut t1, mut rmdr1) = parse_atom(text, ctx)?;
+    loop {
+        rmdr1 = skip_ignored(rmdr1, ctx);
+        match rmdr1.chars().next() {
+            None => break,
+            Some(c1) => match c1 {
+                '*' => t1 = Term::new(TermType::Iteration, vec!(t1)),
+                '+' => t1 = Term::new(TermType::PositiveIteration, vec!(t1)),
+                '?' => t1 = Term::new(TermType::Optional, vec!(t1)),
+                '{' => match scan_repetition(rmdr1, ctx)? {
                     None => break,
-                    Some(c1) => match c1 {
-                        '*' => t1 = Term::new(TermType::Iteration, vec!(t1)),
-                        '+' => t1 = Term::new(TermType::PositiveIteration, vec!(t1)),
-                        '?' => t1 = Term::new(TermType::Optional, vec!(t1)),
-                        _ => break
+                    Some(((min, max), rmdr2)) => {
+                        t1 = Term::new(TermType::Repetition { min, max }, vec!(t1));
+                        rmdr1 = rmdr2;
+                        continue;
                     }
-                }
-                rmdr1 = &rmdr1[1..];
+                },
+                _ => break
             }
-            Some((t1, rmdr1))
         }
+        rmdr1 = &rmdr1[1..];
     }
+    Ok((t1, rmdr1))
 }
 
-fn parse_atom<'a>(text: &'a str, ctx: &mut ParseContext) -> Option<(Term, &'a str)> {
+/// (min, max), where a max of None means unbounded.
+type RepetitionBounds = (usize, Option<usize>);
+
+/**
+ * Scan a bounded repetition quantifier: '{n}', '{n,}' or '{n,m}'.
+ * The text starts at the '{'. If what follows is not shaped like a
+ * quantifier, the brace is just a literal character, and we return None.
+ * Shapes that are right but have impossible bounds ('{3,2}', or numbers
+ * too big to represent) are errors.
+ */
+fn scan_repetition<'a>(
+    text: &'a str,
+    ctx: &ParseContext<'a>
+) -> Result<Option<(RepetitionBounds, &'a str)>, ParseError> {
+    let close = match text.find('}') {
+        None => { return Ok(None); }
+        Some(i) => i,
+    };
+    let inner = &text[1..close];
+    let (lo, hi) = match inner.find(',') {
+        None => (inner, Some(inner)),
+        Some(i) => (&inner[..i], Some(&inner[i + 1..]).filter(|s| !s.is_empty())),
+    };
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_number(lo) || !hi.is_none_or(is_number) {
+        return Ok(None);
+    }
+    let bad = || ctx.error(ParseErrorKind::BadRepetition, text, close + 1);
+    let min: usize = lo.parse().map_err(|_| bad())?;
+    let max: Option<usize> = match hi {
+        None => None,
+        Some(h) => Some(h.parse().map_err(|_| bad())?),
+    };
+    if max.is_some_and(|m| m < min) {
+        return Err(bad());
+    }
+    Ok(Some(((min, max), &text[close + 1..])))
+}
+
+fn parse_atom<'a>(text: &'a str, ctx: &mut ParseContext<'a>) -> ParseResult<'a> {
     //println!("parse_atom '{}'", text);
-    if text.starts_with("(") {
-        if text[1..].starts_with("?") {
-            let rmdr = scan_flags(&text[2..], ctx);
-            parse_atom(rmdr, ctx)
-        } else {
-            match parse_regex(&text[1..], ctx) {
-                None => None,
-                Some((t, rmdr)) => {
-                    if !rmdr.starts_with(")") {
-                        None
-                    } else {
-                        Some((t, &rmdr[1..]))
+    let text = skip_ignored(text, ctx);
+    if let Some(rmdr) = text.strip_prefix('(') {
+        if let Some(flag_text) = rmdr.strip_prefix('?') {
+            let (flags, rmdr) = scan_flags(text, flag_text, ctx)?;
+            if let Some(sub) = rmdr.strip_prefix(':') {
+                // (?flags:subexpr) only applies the flags to subexpr.
+                parse_group(text, sub, flags, ctx)
+            } else {
+                // A bare (?flags) applies to the rest of the enclosing group.
+                ctx.flags = flags;
+                let rmdr = skip_ignored(&rmdr[1..], ctx);
+                match rmdr.chars().next() {
+                    None | Some('|') | Some(')') | Some('/') => {
+                        Ok((Term::new(TermType::Empty, vec!()), rmdr))
                     }
+                    Some(_) => parse_atom(rmdr, ctx),
                 }
             }
+        } else {
+            let flags = ctx.flags;
+            parse_group(text, rmdr, flags, ctx)
         }
-    } else if text.starts_with("\\") {
-        let optc = text.chars().nth(1);
-        match optc {
-            None => panic!("PARSE ERROR: String ends in a backslash"),
-            Some(c) => Some((Term::new(TermType::Atom(c, ctx.no_case), 
-                                       vec!()), 
-                             &text[2..]))
-        }
-    } else if text.starts_with("[") {
-        parse_char_class(&text[1..], ctx)
+    } else if text.starts_with('\\') {
+        let (esc, rmdr) = scan_escape(text, ctx)?;
+        let op = match esc {
+            Escape::Literal(c) => TermType::Atom(c, ctx.flags.no_case),
+            Escape::Class(nc, negated) => {
+                let ccd = CharClassData::new(!negated, vec![CharClassPredicate::Named(nc)]);
+                TermType::CharClassTerm(ccd, ctx.flags.no_case)
+            }
+            Escape::Assertion(kind) => TermType::AssertionTerm(kind),
+            Escape::Invalid => TermType::InvalidTerm,
+        };
+        Ok((Term::new(op, vec!()), rmdr))
+    } else if let Some(rmdr) = text.strip_prefix('[') {
+        parse_char_class(text, rmdr, ctx)
     } else {
-        let c = text.chars().next().unwrap();
-        if c == '.' {
-            Some((Term::new(TermType::AnyCharTerm, vec!()), &text[1..]))
-        } else {
-            Some((Term::new(TermType::Atom(c, ctx.no_case), vec!()), 
-                  &text[1..]))
+        match text.chars().next() {
+            None => Err(ctx.error(ParseErrorKind::MissingOperand, text, 0)),
+            Some(c) if is_operator(c) => {
+                Err(ctx.error(ParseErrorKind::MissingOperand, text, 1))
+            }
+            Some('.') => Ok((Term::new(TermType::AnyCharTerm(ctx.flags.dot_nl), vec!()),
+                             &text[1..])),
+            Some('^') => {
+                let kind = if ctx.flags.multi_line {
+                    Assertion::StartLine
+                } else {
+                    Assertion::StartText
+                };
+                Ok((Term::new(TermType::AssertionTerm(kind), vec!()), &text[1..]))
+            }
+            Some('$') => {
+                let kind = if ctx.flags.multi_line {
+                    Assertion::EndLine
+                } else {
+                    Assertion::EndText
+                };
+                Ok((Term::new(TermType::AssertionTerm(kind), vec!()), &text[1..]))
+            }
+            Some(c) => Ok((Term::new(TermType::Atom(c, ctx.flags.no_case), vec!()),
+                           &text[c.len_utf8()..])),
+        }
+    }
+}
+
+/**
+ * Parse the inside of a group with the given flags, and then restore
+ * the flags that were in effect before it. `open` is the group including
+ * its '(', for error reporting, and `text` is what follows the '(' (and
+ * any flags).
+ */
+fn parse_group<'a>(
+    open: &'a str,
+    text: &'a str,
+    flags: Flags,
+    ctx: &mut ParseContext<'a>
+) -> ParseResult<'a> {
+    let saved = ctx.flags;
+    ctx.flags = flags;
+    let parsed = parse_regex(text, ctx);
+    ctx.flags = saved;
+    let (t, rmdr) = match parsed {
+        // Running out of pattern inside a group means it was never closed.
+        Err(ref e) if e.kind == ParseErrorKind::MissingOperand
+                      && e.offset == ctx.pattern.len() => {
+            return Err(ctx.error(ParseErrorKind::UnbalancedParen, open, 1));
+        }
+        parsed => parsed?,
+    };
+    if rmdr.starts_with('/') {
+        return Err(ctx.error(ParseErrorKind::BadTrailingContext, rmdr, 1));
+    }
+    match rmdr.strip_prefix(')') {
+        None => Err(ctx.error(ParseErrorKind::UnbalancedParen, open, 1)),
+        Some(rest) => Ok((t, rest)),
+    }
+}
+
+/**
+ * In extended (?x) mode, skip whitespace and '#' comments, which run
+ * to the end of the line. Otherwise, everything is significant.
+ */
+fn skip_ignored<'a>(text: &'a str, ctx: &ParseContext<'a>) -> &'a str {
+    if !ctx.flags.extended {
+        return text;
+    }
+    let mut rmdr = text;
+    loop {
+        rmdr = rmdr.trim_start();
+        match rmdr.strip_prefix('#') {
+            None => { return rmdr; }
+            Some(comment) => {
+                rmdr = match comment.find('\n') {
+                    None => "",
+                    Some(i) => &comment[i + 1..],
+                };
+            }
         }
     }
 }
@@ -147,18 +389,19 @@ fn parse_atom<'a>(text: &'a str, ctx: &mut ParseContext) -> Option<(Term, &'a st
  * with the preceding term.
  */
 fn is_operator(ch: char) -> bool {
-    match ch {
-        '|' | '*' | '+' | '?' | ')'  => true,
-        _ => false
-    }
+    matches!(ch, '|' | '*' | '+' | '?' | ')' | '/')
 }
 
 /**
  * The caller has already consumed the leading '[', so text[0] is either
- * '^' or a single char or the start of a char range.
+ * '^' or a single char or the start of a char range. `open` is the
+ * class including its '[', for error reporting.
  */
-fn parse_char_class<'a>(text: &'a str, ctx: &mut ParseContext) -> Option<(Term, &'a str)> {
-    //let mut i = 0;
+fn parse_char_class<'a>(
+    open: &'a str,
+    text: &'a str,
+    ctx: &mut ParseContext<'a>
+) -> ParseResult<'a> {
     let mut rmdr = text;
     let mut negated = false;
     if scan_given("^", rmdr) {
@@ -168,111 +411,262 @@ fn parse_char_class<'a>(text: &'a str, ctx: &mut ParseContext) -> Option<(Term,
     // There must be a character at text[i],
     // but we don't know whether it is a singleton, or the start of a range.
     let mut preds: Vec<CharClassPredicate> = vec![];
-    loop { 
-        match scan_class_elt(rmdr) {
-            None => { break; },
-            Some((pred, nxt)) => {
-                preds.push(pred);
-                rmdr = nxt;
-            }
-        }
+    while let Some((pred, nxt)) = scan_class_elt(rmdr, ctx)? {
+        preds.push(pred);
+        rmdr = nxt;
+    }
+    if rmdr.is_empty() {
+        return Err(ctx.error(ParseErrorKind::UnterminatedClass, open, open.len()));
     }
     rmdr = &rmdr[1..];
+    if preds.is_empty() {
+        let len = open.len() - rmdr.len();
+        return Err(ctx.error(ParseErrorKind::EmptyClass, open, len));
+    }
 
     let ccd = CharClassData::new(!negated, preds);
-    Some((Term::new(TermType::CharClassTerm(ccd, ctx.no_case), vec![]),
-          rmdr))
+    Ok((Term::new(TermType::CharClassTerm(ccd, ctx.flags.no_case), vec![]),
+        rmdr))
 }
 
 /**
  * Scan text for singleton chars and char ranges.
  * Return a char range (in either case), and the position of the 
- * next unread byte in text.
- * Probably needs to be wrapped in an Option.
+ * next unread byte in text, or None at the closing ']' (or at the end
+ * of the text, which the caller reports as an unterminated class).
  * Note that a character might be represented as an escape sequence!
  * E.g., to include ']' or maybe '^'.
- *
- * Someday there will be named classes, but this is not that day.
+ * An element can also be a named class, like '[:alpha:]' or '\d'.
  */
-fn scan_class_elt(text: &str) -> Option<(CharClassPredicate, &str)> {
-    let mut rmdr = text;
-    if scan_given("]", rmdr) {
-        return None;
-    }
-    match scan_class_elt_char(rmdr) {
-        None => { return None; }
-        Some((ch1, rmdr1)) => {
-            rmdr = rmdr1;
-            if ch1 == '[' {
-                // Might be a named character class...
-            }
-            if !scan_given("-", rmdr) {
-                return Some((CharClassPredicate::Individual(ch1), rmdr));
-            }
-            rmdr = &rmdr[1..];
-            match scan_class_elt_char(rmdr) {
-                None => { None }
-                Some((ch2, rmdr2)) => {
-                    Some((CharClassPredicate::Range(ch1, ch2), rmdr2))
-                }
-            }
+fn scan_class_elt<'a>(
+    text: &'a str,
+    ctx: &ParseContext<'a>
+) -> Result<Option<(CharClassPredicate, &'a str)>, ParseError> {
+    if text.is_empty() || scan_given("]", text) {
+        return Ok(None);
+    }
+    if let Some(named) = scan_named_class(text, ctx)? {
+        return Ok(Some(named));
+    }
+    let (ch1, rmdr) = match scan_class_elt_char(text, ctx)? {
+        (Escape::Class(nc, negated), rmdr) => {
+            return Ok(Some((class_predicate(nc, negated), rmdr)));
+        }
+        (Escape::Assertion(_), rmdr) | (Escape::Invalid, rmdr) => {
+            let len = text.len() - rmdr.len();
+            return Err(ctx.error(ParseErrorKind::BadEscape, text, len));
         }
+        (Escape::Literal(c), rmdr) => (c, rmdr),
+    };
+    // A '-' right before the closing ']' is a literal, not a range.
+    if !scan_given("-", rmdr) || rmdr.starts_with("-]") || rmdr.len() == 1 {
+        return Ok(Some((CharClassPredicate::Individual(ch1), rmdr)));
     }
+    let (ch2, rmdr2) = match scan_class_elt_char(&rmdr[1..], ctx)? {
+        (Escape::Literal(c), rmdr2) if c >= ch1 => (c, rmdr2),
+        (_, rmdr2) => {
+            let len = text.len() - rmdr2.len();
+            return Err(ctx.error(ParseErrorKind::BadClassRange, text, len));
+        }
+    };
+    Ok(Some((CharClassPredicate::Range(ch1, ch2), rmdr2)))
 }
 
-fn scan_class_elt_char(text: &str) -> Option<(char, &str)> {
-    let mut bytes = text.bytes();
-    let mut first: u8 = bytes.next().unwrap();
-    let mut start = 0;
-    let end;
-    if first == b'\\' {
-        first = bytes.next().unwrap();
-        start += 1;
-    }
-    if first & 0b1000_0000 == 0b0000_0000 {
-        end = start + 1;
-    } else if first & 0b1110_0000 == 0b1100_0000 {
-        end = start + 2;
-    } else if first & 0b1111_0000 == 0b1110_0000 {
-        end = start + 3;
-    } else if first & 0b1111_1000 == 0b1111_0000 {
-        end = start + 4;
+fn class_predicate(nc: NamedClass, negated: bool) -> CharClassPredicate {
+    if negated {
+        CharClassPredicate::NotNamed(nc)
     } else {
-        unreachable!("UTF8 char scan failed!");
+        CharClassPredicate::Named(nc)
+    }
+}
+
+/**
+ * Scan a POSIX named class, '[:name:]', or its negation '[:^name:]'.
+ * If the text does not start with '[:' ... ':]', it is not a named class,
+ * and the '[' is just a character.
+ */
+fn scan_named_class<'a>(
+    text: &'a str,
+    ctx: &ParseContext<'a>
+) -> Result<Option<(CharClassPredicate, &'a str)>, ParseError> {
+    let rest = match text.strip_prefix("[:") {
+        None => { return Ok(None); }
+        Some(r) => r,
+    };
+    let end = match rest.find(":]") {
+        None => { return Ok(None); }
+        Some(i) => i,
+    };
+    let (name, negated) = match rest[..end].strip_prefix('^') {
+        None => (&rest[..end], false),
+        Some(nm) => (nm, true),
+    };
+    match NamedClass::from_name(name) {
+        None => Err(ctx.error(ParseErrorKind::BadClassName, text, end + 4)),
+        Some(nc) => Ok(Some((class_predicate(nc, negated), &rest[end + 2..]))),
     }
+}
+
+/**
+ * Scan one (possibly backslash-escaped) character. The text must not
+ * be empty. Inside brackets, escapes like '\d' stand for a class.
+ */
+fn scan_class_elt_char<'a>(
+    text: &'a str,
+    ctx: &ParseContext<'a>
+) -> Result<(Escape, &'a str), ParseError> {
+    if text.starts_with('\\') {
+        return scan_escape(text, ctx);
+    }
+    let c = text.chars().next().unwrap();
+    Ok((Escape::Literal(c), &text[c.len_utf8()..]))
+}
+
+
+/**
+ * What a backslash escape stands for: either a single character,
+ * one of the Perl-style shorthand classes (possibly negated), a
+ * zero-width assertion, or an invalid UTF-8 sequence (those last two
+ * only make sense outside brackets).
+ */
+enum Escape {
+    Literal(char),
+    Class(NamedClass, bool),
+    Assertion(Assertion),
+    Invalid,
+}
+
+/**
+ * Scan an escape sequence. The text starts at the backslash.
+ *
+ * <escape> ::= 'd' | 'D' | 'w' | 'W' | 's' | 'S'
+ *            | ('p' | 'P') <letter> | ('p' | 'P') '{' <class-name> '}'
+ *            | 'b' | 'B' | 'A' | 'z'
+ *            | 'I'
+ *            | 'n' | 't' | 'r'
+ *            | 'x' <hex> <hex>
+ *            | 'u' '{' <hex>+ '}'
+ *            | <any other char, taken literally>
+ *
+ * '\I' matches one invalid UTF-8 sequence, where bytes are tokenized
+ * with rebytes::InvalidUtf8::Token. It is not a character, so it
+ * matches nothing else: not even U+FFFD, and not in text that was
+ * valid to begin with.
+ */
+fn scan_escape<'a>(
+    text: &'a str,
+    ctx: &ParseContext<'a>
+) -> Result<(Escape, &'a str), ParseError> {
+    let c = match text[1..].chars().next() {
+        None => { return Err(ctx.error(ParseErrorKind::DanglingBackslash, text, 1)); }
+        Some(c) => c,
+    };
+    let rmdr = &text[1 + c.len_utf8()..];
+    let esc = match c {
+        'd' => Escape::Class(NamedClass::Digit, false),
+        'D' => Escape::Class(NamedClass::Digit, true),
+        'w' => Escape::Class(NamedClass::Word, false),
+        'W' => Escape::Class(NamedClass::Word, true),
+        's' => Escape::Class(NamedClass::Space, false),
+        'S' => Escape::Class(NamedClass::Space, true),
+        'n' => Escape::Literal('\n'),
+        't' => Escape::Literal('\t'),
+        'r' => Escape::Literal('\r'),
+        'b' => Escape::Assertion(Assertion::WordBoundary),
+        'B' => Escape::Assertion(Assertion::NotWordBoundary),
+        'A' => Escape::Assertion(Assertion::StartText),
+        'z' => Escape::Assertion(Assertion::EndText),
+        'I' => Escape::Invalid,
+        'p' | 'P' => {
+            let (name, after) = match rmdr.chars().next() {
+                Some('{') => match rmdr.find('}') {
+                    None => { return Err(ctx.error(ParseErrorKind::BadEscape, text, 2)); }
+                    Some(close) => (&rmdr[1..close], &rmdr[close + 1..]),
+                },
+                Some(l) => rmdr.split_at(l.len_utf8()),
+                None => { return Err(ctx.error(ParseErrorKind::BadEscape, text, 2)); }
+            };
+            return match UnicodeClass::from_name(name) {
+                None => {
+                    let len = text.len() - after.len();
+                    Err(ctx.error(ParseErrorKind::BadClassName, text, len))
+                }
+                Some(uc) => Ok((Escape::Class(NamedClass::Unicode(uc), c == 'P'), after)),
+            };
+        }
+        'x' => {
+            let digits = rmdr.get(..2).filter(|d| is_hex(d));
+            return match digits {
+                None => Err(ctx.error(ParseErrorKind::BadEscape, text, 2)),
+                Some(d) => Ok((Escape::Literal(hex_char(d).unwrap()), &rmdr[2..])),
+            };
+        }
+        'u' => {
+            let close = match rmdr.find('}') {
+                Some(i) if rmdr.starts_with('{') => i,
+                _ => { return Err(ctx.error(ParseErrorKind::BadEscape, text, 2)); }
+            };
+            let digits = &rmdr[1..close];
+            let ch = if digits.len() <= 6 && is_hex(digits) { hex_char(digits) } else { None };
+            return match ch {
+                None => Err(ctx.error(ParseErrorKind::BadEscape, text, close + 3)),
+                Some(ch) => Ok((Escape::Literal(ch), &rmdr[close + 1..])),
+            };
+        }
+        _ => Escape::Literal(c),
+    };
+    Ok((esc, rmdr))
+}
 
-    let c = text[start..].chars().next().unwrap();
+fn is_hex(digits: &str) -> bool {
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_hexdigit())
+}
 
-    Some((c, &text[end..]))
+/**
+ * The character with the given hex code point, if there is one.
+ */
+fn hex_char(digits: &str) -> Option<char> {
+    u32::from_str_radix(digits, 16).ok().and_then(::std::char::from_u32)
 }
 
 
 /**
- * When this is called, we have already consumed the "(?" prefix
+ * When this is called, we have already consumed the "(?" prefix.
+ * `open` is the text starting at the '(', for error reporting.
+ * Returns the current flags as modified by the flag settings, and the
+ * text starting at the ':' or ')' that ends them. The context's flags
+ * are left alone; it is up to the caller to decide on their scope.
+ * '(?:' may have no flags at all, but a bare '(?)' is an error.
  *
  * <flags> ::= '(' '?' <flag-setting>+ ')'
  * <flag-setting> ::= '-'? <flag-letter>
- * <flag-letter> ::= 'i'
+ * <flag-letter> ::= 'i' | 's' | 'm' | 'x'
  */
-fn scan_flags<'a>(text: &'a str, ctx: &mut ParseContext) -> &'a str /*-> Option<(Term, &str)>*/ {
+fn scan_flags<'a>(
+    open: &'a str,
+    text: &'a str,
+    ctx: &ParseContext<'a>
+) -> Result<(Flags, &'a str), ParseError> {
+    let mut flags = ctx.flags;
     let mut unset = false;
-    let mut i = 0;
-    for c in text.bytes() {
-        i += 1;
+    let mut any = false;
+    for (i, c) in text.char_indices() {
         match c {
-            b')' => { break; }
-            b'-' => { unset = true; }
-            b'i' => {
-                if unset {
-                    ctx.no_case = false; 
-                } else {
-                    ctx.no_case = true; 
-                }
+            ')' if !any => {
+                return Err(ctx.error(ParseErrorKind::MissingFlag, &text[i..], 1));
+            }
+            ')' | ':' => { return Ok((flags, &text[i..])); }
+            '-' => { unset = true; }
+            'i' => { flags.no_case = !unset; any = true; }
+            's' => { flags.dot_nl = !unset; any = true; }
+            'm' => { flags.multi_line = !unset; any = true; }
+            'x' => { flags.extended = !unset; any = true; }
+            _ => {
+                return Err(ctx.error(ParseErrorKind::BadFlag, &text[i..], 1));
             }
-            _ => { panic!("PARSE ERROR: Unrecognized flag character {}", c); }
         }
     }
-    &text[i..]
+    Err(ctx.error(ParseErrorKind::UnbalancedParen, open, 1))
 }
 
 
@@ -284,6 +678,146 @@ fn scan_flags<'a>(text: &'a str, ctx: &mut ParseContext) -> &'a str /*-> Option<
  * The caller has to manage that.
  */
 fn scan_given(ch: &str, text: &str) -> bool {
-    return ch.as_bytes()[0] == text.as_bytes()[0];
+    text.starts_with(ch)
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::ParseErrorKind::*;
+    use testutil::matches_whole;
+
+    fn error(pattern: &str) -> ParseError {
+        match parse(pattern) {
+            Ok(t) => panic!("{:?} parsed as {:?}", pattern, t),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn error_kinds_and_offsets() {
+        let cases = [
+            ("(ab", UnbalancedParen, 0, "("),
+            ("a(", UnbalancedParen, 1, "("),
+            ("a(b|", UnbalancedParen, 1, "("),
+            ("ab)", UnbalancedParen, 2, ")"),
+            ("(?i", UnbalancedParen, 0, "("),
+            ("ab\\", DanglingBackslash, 2, "\\"),
+            ("a[bc", UnterminatedClass, 1, "[bc"),
+            ("a[]", EmptyClass, 1, "[]"),
+            ("[^]", EmptyClass, 0, "[^]"),
+            ("(?iq)", BadFlag, 3, "q"),
+            ("a(?)", MissingFlag, 3, ")"),
+            ("(?-)", MissingFlag, 3, ")"),
+            ("a|*", MissingOperand, 2, "*"),
+            ("a|", MissingOperand, 2, ""),
+            ("a{3,2}", BadRepetition, 1, "{3,2}"),
+            ("[[:foo:]]", BadClassName, 1, "[:foo:]"),
+            ("\\p{Foo}x", BadClassName, 0, "\\p{Foo}"),
+            ("[z-a]", BadClassRange, 1, "z-a"),
+            ("[\\b]", BadEscape, 1, "\\b"),
+            ("\\x4", BadEscape, 0, "\\x"),
+            ("a/b/c", BadTrailingContext, 3, "/"),
+            ("(a/b)", BadTrailingContext, 2, "/"),
+        ];
+        for &(pattern, kind, offset, text) in cases.iter() {
+            let e = error(pattern);
+            assert_eq!((e.kind, e.offset, e.text.as_str()), (kind, offset, text),
+                       "{:?}", pattern);
+            assert_eq!(e.pattern, pattern);
+        }
+    }
+
+    #[test]
+    fn flags_may_be_empty_before_colon() {
+        assert!(parse("(?:ab)").is_ok());
+        assert!(parse("(?i)ab").is_ok());
+    }
+
+    #[test]
+    fn display_puts_caret_under_offset() {
+        assert_eq!(error("(ab").to_string(),
+                   "regex parse error: unbalanced parenthesis at offset 0: '('\n    \
+                    (ab\n    ^");
+        // The caret counts characters, not bytes.
+        assert_eq!(error("é[z-a]").to_string(),
+                   "regex parse error: invalid character class range at offset 3: 'z-a'\n    \
+                    é[z-a]\n      ^");
+    }
+
+    #[test]
+    fn shorthand_classes() {
+        let cases = [
+            (r"\d", "7", true), (r"\d", "٣", true), (r"\d", "x", false),
+            (r"\D", "x", true), (r"\D", "7", false),
+            (r"\w", "é", true), (r"\w", "_", true), (r"\w", "-", false),
+            (r"\W", "-", true), (r"\W", "a", false),
+            (r"\s", "\t", true), (r"\s", "\u{2028}", true), (r"\s", "a", false),
+            (r"\S", "a", true), (r"\S", " ", false),
+            (r"[\d\s]+", "1 2", true), (r"[^\d]", "1", false), (r"[\D]", "a", true),
+            (r"[a\W]+", "a-a", true), (r"[a\W]+", "ab", false),
+        ];
+        for &(rule, text, want) in cases.iter() {
+            assert_eq!(matches_whole(rule, text), want, "{:?} on {:?}", rule, text);
+        }
+    }
+
+    #[test]
+    fn character_escapes() {
+        let cases = [
+            (r"a\nb", "a\nb"), (r"\t\r", "\t\r"), (r"\x41\x7e", "A~"),
+            (r"\u{e9}\u{1F600}", "é😀"), (r"[\x30-\x39]+", "0189"),
+            (r"[\n\t]+", "\t\n"), (r"\.\*\[", ".*["),
+        ];
+        for &(rule, text) in cases.iter() {
+            assert!(matches_whole(rule, text), "{:?} on {:?}", rule, text);
+        }
+        assert!(!matches_whole(r"\x41", "a"));
+        assert_eq!(error(r"\u{D800}").kind, BadEscape);
+        assert_eq!(error(r"\u{110000}").kind, BadEscape);
+        assert_eq!(error(r"\u{41").kind, BadEscape);
+        assert_eq!(error(r"\xZZ").kind, BadEscape);
+    }
+
+    #[test]
+    fn flags_are_scoped_to_their_group() {
+        let cases = [
+            ("(?i:a)b", "Ab", true),
+            ("(?i:a)b", "AB", false),
+            ("(a(?i)b)c", "aBc", true),
+            ("(a(?i)b)c", "aBC", false),
+            ("(a(?i)b)c", "ABc", false),
+            // A bare setting lasts to the end of the group, across '|'.
+            ("x(?i)a|b", "B", true),
+            ("(?i)a(?-i)b", "Ab", true),
+            ("(?i)a(?-i)b", "AB", false),
+            ("(?i)(?-i:a)b", "aB", true),
+            ("(?i)(?-i:a)b", "AB", false),
+            ("((?i)a)|b", "B", false),
+        ];
+        for &(rule, text, want) in cases.iter() {
+            assert_eq!(matches_whole(rule, text), want, "{:?} on {:?}", rule, text);
+        }
+    }
+
+    #[test]
+    fn dot_newline_multi_line_and_extended_flags() {
+        let cases = [
+            ("a.b", "a\nb", false),
+            ("(?s)a.b", "a\nb", true),
+            ("(?s:.)(?-s:.)", "\n\n", false),
+            ("a$\n^b", "a\nb", false),
+            ("(?m)a$\n^b", "a\nb", true),
+            ("(?x) a b # a comment\n c", "abc", true),
+            ("(?x) a +", "aaa", true),
+            ("(?x)a\\ b", "a b", true),
+            ("(?x)a[ ]b", "a b", true),
+            ("(?x)a b", "a b", false),
+            ("(?x: a )b c", "ab c", true),
+        ];
+        for &(rule, text, want) in cases.iter() {
+            assert_eq!(matches_whole(rule, text), want, "{:?} on {:?}", rule, text);
+        }
+    }
+}
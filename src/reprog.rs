@@ -4,15 +4,20 @@
 use std::ops::{Index, IndexMut};
 use std::fmt;
 use std::collections::HashMap;
-use reterm::CharClassData;
+use reterm::{CharClassData, Assertion};
 
 pub type Label = usize;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Instruction {
     Char(CharInstData),
     AnyChar(AnyCharInst),
     CharClass(CharClassInst),
+    ByteRange(ByteRangeInst),     // byte mode only; see Program::byte_mode()
+    ByteSwitch(ByteSwitchInst),   // byte mode only
+    Assert(AssertInst),           // zero-width
+    Invalid(Label),               // an invalid UTF-8 sequence (see rebytes.rs)
+    Mark(Label),                  // end of r in r/s; zero-width
     Match(MatchInst),             // arg: rule#
     Split(Label, Label),
 }
@@ -21,12 +26,15 @@ pub enum Instruction {
 #[derive(Clone, Copy, Debug)]
 pub struct CharInstData {
     pub ch: char,
-    pub nocase: bool,
     pub goto: Label,
 }
 
+/**
+ * Matches any character, except '\n' unless dot_nl is set.
+ */
 #[derive(Clone, Copy, Debug)]
 pub struct AnyCharInst {
+    pub dot_nl: bool,
     pub goto: Label,
 }
 
@@ -36,25 +44,77 @@ pub struct MatchInst {
     //pub goto: Label,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CharClassInst {
     pub data: CharClassData,
-    pub nocase: bool,
     pub goto: Label,
 }
 
+/**
+ * Matches one byte in lo..=hi (of the UTF-8 encoding of the text).
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct ByteRangeInst {
+    pub lo: u8,
+    pub hi: u8,
+    pub goto: Label,
+}
 
+/**
+ * Matches one byte in any of several ranges, going on to wherever that
+ * range goes: a split between ByteRange instructions, as a single
+ * instruction. The arms are sorted and do not overlap, so a byte takes
+ * at most one of them.
+ */
+#[derive(Clone, Debug)]
+pub struct ByteSwitchInst {
+    pub arms: Vec<ByteRangeInst>,
+}
+
+impl ByteSwitchInst {
+    /**
+     * Where the arm that takes byte b goes, if there is one.
+     */
+    pub fn goto(&self, b: u8) -> Option<Label> {
+        self.arms.binary_search_by(|arm| {
+            if arm.hi < b {
+                ::std::cmp::Ordering::Less
+            } else if arm.lo > b {
+                ::std::cmp::Ordering::Greater
+            } else {
+                ::std::cmp::Ordering::Equal
+            }
+        }).ok().map(|i| self.arms[i].goto)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AssertInst {
+    pub kind: Assertion,
+    pub goto: Label,
+}
 
 
 impl fmt::Display for Instruction {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use self::Instruction::*;
         match *self {
-            Char(ref data) =>  write!(f, "char {} goto {} {}", data.ch, data.goto,
-                if data.nocase { " [nocase]" } else { "" }),
-            AnyChar(ref data) => write!(f, "any_char goto {}", data.goto),
-            CharClass(ref cc) => write!(f, "{} goto {} {}", cc.data, cc.goto,
-                if cc.nocase { " [nocase]" } else { "" }),
+            Char(ref data) =>  write!(f, "char {} goto {}", data.ch, data.goto),
+            AnyChar(ref data) => write!(f, "any_char goto {} {}", data.goto,
+                if data.dot_nl { " [dot_nl]" } else { "" }),
+            CharClass(ref cc) => write!(f, "{} goto {}", cc.data, cc.goto),
+            ByteRange(ref br) => write!(f, "byte [{:02x}-{:02x}] goto {}", br.lo, br.hi, br.goto),
+            ByteSwitch(ref bs) => {
+                write!(f, "byte switch")?;
+                for (i, arm) in bs.arms.iter().enumerate() {
+                    let sep = if i == 0 { " " } else { ", " };
+                    write!(f, "{}[{:02x}-{:02x}] goto {}", sep, arm.lo, arm.hi, arm.goto)?;
+                }
+                Ok(())
+            }
+            Assert(ref data) => write!(f, "assert {} goto {}", data.kind, data.goto),
+            Invalid(l) => write!(f, "invalid goto {}", l),
+            Mark(l) => write!(f, "mark goto {}", l),
             Match(ref data) => write!(f, "match {}", data.rule_id),
             Split(l1, l2) => write!(f, "split {}, {}", l1, l2),
         }
@@ -64,10 +124,143 @@ impl fmt::Display for Instruction {
 
 
 
-#[derive(Debug)]
+/**
+ * The lengths, in characters, of r and s in a trailing context rule
+ * r/s, where they are fixed. If either one is, we can tell where r ends
+ * in a match without running anything.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrailInfo {
+    pub head_len: Option<usize>,
+    pub tail_len: Option<usize>,
+}
+
+impl TrailInfo {
+    /**
+     * Given the text matched by the whole of r/s, the byte length of
+     * the part matched by r, if the fixed lengths are enough to say.
+     */
+    pub fn fixed_split(&self, whole: &str) -> Option<usize> {
+        match (self.head_len, self.tail_len) {
+            (Some(n), _) => Some(whole.char_indices().nth(n).map_or(whole.len(), |(i, _)| i)),
+            (None, Some(0)) => Some(whole.len()),
+            (None, Some(n)) => Some(whole.char_indices().rev().nth(n - 1).map_or(0, |(i, _)| i)),
+            (None, None) => None,
+        }
+    }
+}
+
+
+/**
+ * A partition of the byte values into classes that a byte mode program
+ * can not tell apart: no ByteRange instruction (or ByteSwitch arm)
+ * takes some bytes of a class but not others, and neither do
+ * assertions, which care about newlines, word characters and non-ASCII
+ * bytes. So a DFA only needs a transition per class, rather than per
+ * byte.
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ByteClasses {
+    classes: [u8; 256],
+    count: usize,
+}
+
+impl ByteClasses {
+    /**
+     * Put bytes in the same class when they are in exactly the same
+     * ranges. Classes are numbered in order of their first byte.
+     */
+    fn from_ranges(ranges: &[(u8, u8)]) -> ByteClasses {
+        let mut classes = [0; 256];
+        let mut ids: HashMap<Vec<bool>, usize> = HashMap::new();
+        for b in 0..=255u8 {
+            let sig: Vec<bool> = ranges.iter().map(|&(lo, hi)| lo <= b && b <= hi).collect();
+            let next_id = ids.len();
+            classes[b as usize] = *ids.entry(sig).or_insert(next_id) as u8;
+        }
+        ByteClasses { classes, count: ids.len() }
+    }
+
+    /**
+     * The class of a byte, from 0 to count() - 1.
+     */
+    pub fn get(&self, b: u8) -> usize {
+        self.classes[b as usize] as usize
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /**
+     * The first byte of each class, in class order.
+     */
+    pub fn representatives(&self) -> Vec<u8> {
+        let mut reps = vec![];
+        for b in 0..=255u8 {
+            if self.get(b) == reps.len() {
+                reps.push(b);
+            }
+        }
+        reps
+    }
+}
+
+
+/**
+ * Some numbers about a program, to show how big it is.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramStats {
+    pub instructions: usize,
+    pub rules: usize,
+    pub byte_classes: Option<usize>,    // in byte mode
+}
+
+impl fmt::Display for ProgramStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} rules, {} instructions", self.rules, self.instructions)?;
+        if let Some(n) = self.byte_classes {
+            write!(f, ", {} byte classes", n)?;
+        }
+        Ok(())
+    }
+}
+
+
+/**
+ * The start condition every program has, and starts out in.
+ */
+pub const INITIAL: usize = 0;
+
+/**
+ * A lex start condition: a named state in which only some rules are
+ * active. Rules compiled without naming any conditions are active in
+ * INITIAL and in every inclusive (%s) condition, but not in exclusive
+ * (%x) ones. `starts` lists the entry points of the rules that name
+ * this condition.
+ */
+#[derive(Debug, Clone)]
+pub struct StartCondition {
+    pub name: String,
+    pub exclusive: bool,
+    pub starts: Vec<Label>,
+}
+
+#[derive(Debug, Clone)]
 pub struct Program {
     code: Vec<Instruction>,
     pub starts: Vec<usize>,         // entry points
+    pub conditions: Vec<StartCondition>,
+    unconditional: Vec<Label>,      // entry points of rules naming no condition
+    trailing: HashMap<usize, (Label, TrailInfo)>,   // r/s rules: entry point, lengths
+    byte_mode: bool,
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Program {
@@ -75,21 +268,144 @@ impl Program {
         Program {
             code: vec![],
             starts: vec![],
+            conditions: vec![StartCondition {
+                name: "INITIAL".to_string(),
+                exclusive: false,
+                starts: vec![],
+            }],
+            unconditional: vec![],
+            trailing: HashMap::new(),
+            byte_mode: false,
         }
     }
     pub fn len(&self) -> usize {
         self.code.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
     pub fn push(&mut self, instr: Instruction) {
         self.code.push(instr);
     }
+    /**
+     * Whether the program works on the bytes of the UTF-8 encoding of
+     * the text, with ByteRange and ByteSwitch instructions, rather than
+     * on characters, with Char, AnyChar and CharClass ones. It is one
+     * or the other.
+     */
+    pub fn byte_mode(&self) -> bool {
+        self.byte_mode
+    }
+    pub fn set_byte_mode(&mut self, byte_mode: bool) {
+        self.byte_mode = byte_mode;
+    }
+    /**
+     * Whether any rule uses \b or \B, which need to know whether the
+     * characters around them are word characters.
+     */
+    pub fn has_word_assertions(&self) -> bool {
+        self.code.iter().any(|inst| match *inst {
+            Instruction::Assert(ref data) => data.kind.is_word_boundary(),
+            _ => false,
+        })
+    }
+    /**
+     * The byte classes of a byte mode program.
+     */
+    pub fn byte_classes(&self) -> ByteClasses {
+        // What assertions (and relazy::Context) can tell apart.
+        let mut ranges = vec![(b'\n', b'\n'), (b'0', b'9'), (b'A', b'Z'), (b'_', b'_'),
+                              (b'a', b'z'), (0x80, 0xFF)];
+        for inst in &self.code {
+            match *inst {
+                Instruction::ByteRange(ref br) => ranges.push((br.lo, br.hi)),
+                Instruction::ByteSwitch(ref bs) => {
+                    ranges.extend(bs.arms.iter().map(|arm| (arm.lo, arm.hi)));
+                }
+                _ => {}
+            }
+        }
+        ranges.sort();
+        ranges.dedup();
+        ByteClasses::from_ranges(&ranges)
+    }
+    pub fn stats(&self) -> ProgramStats {
+        ProgramStats {
+            instructions: self.code.len(),
+            rules: self.starts.len(),
+            byte_classes: if self.byte_mode { Some(self.byte_classes().count()) } else { None },
+        }
+    }
     pub fn print(&self) {
         for (pos, inst) in self.code.iter().enumerate() {
             println!("{:03}: {}", pos, *inst);
         }
     }
+    /**
+     * Add the entry point of a rule that names no start conditions.
+     */
     pub fn add_start(&mut self, start: usize) {
         self.starts.push(start);
+        self.unconditional.push(start);
+    }
+    /**
+     * Add the entry point of a rule that is active only in the given
+     * start conditions.
+     */
+    pub fn add_start_in(&mut self, start: usize, conditions: &[usize]) {
+        self.starts.push(start);
+        for &sc in conditions {
+            self.conditions[sc].starts.push(start);
+        }
+    }
+    /**
+     * Note that a rule has trailing context, and starts at `start`.
+     */
+    pub fn add_trailing(&mut self, rule: usize, start: Label, info: TrailInfo) {
+        self.trailing.insert(rule, (start, info));
+    }
+    /**
+     * The entry point and lengths of a rule with trailing context, or
+     * None for an ordinary rule.
+     */
+    pub fn trailing(&self, rule: usize) -> Option<(Label, TrailInfo)> {
+        self.trailing.get(&rule).cloned()
+    }
+    pub fn has_trailing(&self) -> bool {
+        !self.trailing.is_empty()
+    }
+    /**
+     * The rules with trailing context, in order.
+     */
+    pub fn trailing_rules(&self) -> Vec<usize> {
+        let mut rules: Vec<usize> = self.trailing.keys().cloned().collect();
+        rules.sort();
+        rules
+    }
+    pub fn add_condition(&mut self, name: &str, exclusive: bool) -> usize {
+        self.conditions.push(StartCondition {
+            name: name.to_string(),
+            exclusive,
+            starts: vec![],
+        });
+        self.conditions.len() - 1
+    }
+    pub fn condition_id(&self, name: &str) -> Option<usize> {
+        self.conditions.iter().position(|sc| sc.name == name)
+    }
+    /**
+     * The entry points of all the rules active in a start condition,
+     * sorted.
+     */
+    pub fn condition_starts(&self, sc: usize) -> Vec<Label> {
+        let cond = &self.conditions[sc];
+        let mut starts = cond.starts.clone();
+        if !cond.exclusive {
+            starts.extend_from_slice(&self.unconditional);
+        }
+        starts.sort();
+        starts.dedup();
+        starts
     }
     pub fn ground_labels(&mut self, lblmap: &HashMap<Label,Label>) {
         use self::Instruction::*;
@@ -100,22 +416,45 @@ impl Program {
                 Char(ref data) => { 
                     code_new.push(Char(CharInstData {
                         ch: data.ch, 
-                        nocase: data.nocase,
                         goto: lblmap[&data.goto],
                     }));
                 }
                 AnyChar(ref data) => {
                     code_new.push(AnyChar(AnyCharInst {
+                        dot_nl: data.dot_nl,
                         goto: lblmap[&data.goto],
                     }));
                 }
                 CharClass(ref ccdata) => {
                     code_new.push(CharClass(CharClassInst {
                         data: ccdata.data.clone(),
-                        nocase: ccdata.nocase,
                         goto: lblmap[&ccdata.goto],
                     }));
                 }
+                ByteRange(ref br) => {
+                    code_new.push(ByteRange(ByteRangeInst {
+                        goto: lblmap[&br.goto],
+                        ..*br
+                    }));
+                }
+                ByteSwitch(ref bs) => {
+                    let arms = bs.arms.iter()
+                        .map(|arm| ByteRangeInst { goto: lblmap[&arm.goto], ..*arm })
+                        .collect();
+                    code_new.push(ByteSwitch(ByteSwitchInst { arms }));
+                }
+                Assert(ref data) => {
+                    code_new.push(Assert(AssertInst {
+                        kind: data.kind,
+                        goto: lblmap[&data.goto],
+                    }));
+                }
+                Invalid(l) => {
+                    code_new.push(Invalid(lblmap[&l]));
+                }
+                Mark(l) => {
+                    code_new.push(Mark(lblmap[&l]));
+                }
                 Match(ref data) => {
                     code_new.push(Match(MatchInst {
                         rule_id: data.rule_id,
@@ -0,0 +1,350 @@
+/*
+ * The state machine behind reinterp::Tokens and retoken::Scan.
+ *
+ * Both walk a text, asking the engine for the matches at the current
+ * position and moving past the token they pick. Tokens just takes the
+ * best match; Scan offers the matches to its actions, best first, and
+ * does what they say (see retoken::ActionResult). Here, Tokens is the
+ * case where every action accepts its token, and nothing changes the
+ * start condition.
+ *
+ * Where they are is a Progress, which can be taken out and handed to a
+ * new Tokens or Scan later on. That is how restream.rs carries on once
+ * it has read more of the input, and how rebytes.rs keeps its place
+ * between tokens.
+ *
+ * The order actions run in around a run of unmatched text is set out
+ * in retoken.rs.
+ */
+
+use reinterp::{next_char, MatchEngine, MatchRecord, NoMatchError, Token, UnmatchedPolicy};
+use reinterp::{INVALID, UNMATCHED};
+use retoken::{ActionResult, TokenizeError};
+use rebytes::{invalid_at, invalid_token, invalid_within, past_invalid};
+
+
+/**
+ * What a Scanner asks of the actions, if any.
+ */
+pub(crate) trait Actions {
+    /**
+     * The start condition to match in, or None to leave the engine in
+     * the one it is in.
+     */
+    fn condition(&self) -> Option<usize>;
+
+    /**
+     * Run the action for a token: its rule's, or for UNMATCHED and
+     * INVALID tokens, the unmatched action.
+     */
+    fn run(&mut self, tok: &Token) -> ActionResult;
+
+    /**
+     * Change the start condition, if `result` (from run(), for a token
+     * that is kept) says to.
+     */
+    fn follow(&mut self, result: ActionResult);
+}
+
+/**
+ * The actions of reinterp::Tokens: keep every token.
+ */
+pub(crate) struct NoActions;
+
+impl Actions for NoActions {
+    fn condition(&self) -> Option<usize> {
+        None
+    }
+
+    fn run(&mut self, _tok: &Token) -> ActionResult {
+        ActionResult::Accept
+    }
+
+    fn follow(&mut self, _result: ActionResult) {}
+}
+
+
+/**
+ * Where the text a Scanner is working on sits in the whole input, when
+ * that is being read a piece at a time (see restream.rs). Token offsets
+ * are into the whole input, while Progress offsets are into the text
+ * at hand.
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Window {
+    pub(crate) base: usize,     // offset of the text in the whole input
+    pub(crate) partial: bool,   // more input may follow the text
+    pub(crate) starved: bool,   // stopped because it needs more input
+}
+
+impl Window {
+    /**
+     * Whether we have to stop and wait for more input, because the
+     * matches we have might not hold once it comes. Unmatched runs and
+     * the end of the text have to wait too.
+     */
+    fn starve(&mut self, hit_end: bool) -> bool {
+        self.starved = self.partial && hit_end;
+        self.starved
+    }
+}
+
+
+/**
+ * How far a Scanner has got. Offsets are into its text.
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Progress {
+    pos: usize,
+    run_start: Option<usize>,   // start of the unmatched run, when coalescing
+    pending: Option<(usize, usize, usize)>,     // (rule, start, end) of the token after a run
+    window: Window,
+    error: Option<TokenizeError>,
+}
+
+impl Progress {
+    /**
+     * The start of a text seen through `window`.
+     */
+    pub(crate) fn new(window: Window) -> Progress {
+        Progress { window, ..Progress::default() }
+    }
+
+    /**
+     * Why we stopped, if we stopped early.
+     */
+    pub(crate) fn error(&self) -> Option<TokenizeError> {
+        self.error
+    }
+
+    /**
+     * Whether we stopped to wait for more input.
+     */
+    pub(crate) fn starved(&self) -> bool {
+        self.window.starved
+    }
+
+    /**
+     * The earliest offset into the text that is still needed.
+     */
+    pub(crate) fn needed_from(&self) -> usize {
+        let mut from = self.pos;
+        if let Some(run_start) = self.run_start {
+            from = from.min(run_start);
+        }
+        if let Some((_, start, _)) = self.pending {
+            from = from.min(start);
+        }
+        from
+    }
+
+    /**
+     * The first `cut` bytes of the text are gone, and what is left is
+     * seen through `window`.
+     */
+    pub(crate) fn shift(&mut self, cut: usize, window: Window) {
+        self.pos -= cut;
+        self.run_start = self.run_start.map(|r| r - cut);
+        self.pending = self.pending.map(|(rule, start, end)| (rule, start - cut, end - cut));
+        self.window = window;
+    }
+}
+
+
+/**
+ * The state machine itself. Each call to next() gets it the engine,
+ * and the actions.
+ */
+pub(crate) struct Scanner<'t> {
+    text: &'t str,
+    invalid: &'t [(usize, usize)],     // see MatchEngine::all_matches_at_decoded()
+    policy: UnmatchedPolicy,
+    progress: Progress,
+}
+
+impl<'t> Scanner<'t> {
+    pub(crate) fn new(text: &'t str) -> Scanner<'t> {
+        Scanner {
+            text,
+            invalid: &[],
+            policy: UnmatchedPolicy::default(),
+            progress: Progress::default(),
+        }
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: UnmatchedPolicy) {
+        self.policy = policy;
+    }
+
+    pub(crate) fn set_invalid(&mut self, invalid: &'t [(usize, usize)]) {
+        self.invalid = invalid;
+    }
+
+    /**
+     * Carry on from `progress`, which came from a Scanner over the same
+     * text (or what is left of it after Progress::shift()).
+     */
+    pub(crate) fn resume(&mut self, progress: Progress) {
+        self.progress = progress;
+    }
+
+    pub(crate) fn progress(&self) -> Progress {
+        self.progress
+    }
+
+    pub(crate) fn error(&self) -> Option<TokenizeError> {
+        self.progress.error
+    }
+
+    pub(crate) fn next<E, A>(&mut self, engine: &mut E, actions: &mut A) -> Option<Token<'t>>
+        where E: MatchEngine + ?Sized,
+              A: Actions
+    {
+        if let Some((rule, start, end)) = self.progress.pending.take() {
+            return Some(self.token(rule, start, end));
+        }
+        let text = self.text;
+        while self.progress.pos < text.len() {
+            let start = self.progress.pos;
+            if let Some(sc) = actions.condition() {
+                if let Err(e) = engine.set_start_condition(sc) {
+                    self.stop(e.into());
+                    return None;
+                }
+            }
+            engine.all_matches_at_decoded(text, start, self.invalid);
+            if self.progress.window.starve(engine.hit_end()) {
+                return None;
+            }
+
+            let tok = match self.choose(engine, actions, start) {
+                Some((tok, len, result)) => {
+                    self.progress.pos = start + len;
+                    let run = self.end_run(actions, start);
+                    actions.follow(result);
+                    (run, if result == ActionResult::Skip { None } else { Some(tok) })
+                }
+                None => match invalid_at(self.invalid, start) {
+                    Some(end) => {
+                        self.progress.pos = end;
+                        let run = self.end_run(actions, start);
+                        (run, self.settle(actions, INVALID, start, end))
+                    }
+                    None => {
+                        let end = next_char(text, start);
+                        self.progress.pos = end;
+                        match self.policy {
+                            UnmatchedPolicy::SkipChar => (None, self.settle(actions, UNMATCHED, start, end)),
+                            UnmatchedPolicy::Coalesce => {
+                                self.progress.run_start.get_or_insert(start);
+                                continue;
+                            }
+                            UnmatchedPolicy::Stop => {
+                                let pos = self.progress.window.base + start;
+                                self.stop(NoMatchError { pos }.into());
+                                return None;
+                            }
+                        }
+                    }
+                },
+            };
+
+            match tok {
+                (Some(run), tok) => {
+                    let base = self.progress.window.base;
+                    self.progress.pending = tok.map(|tok| (tok.rule, tok.start - base, tok.end - base));
+                    return Some(run);
+                }
+                (None, Some(tok)) => return Some(tok),
+                (None, None) => {}
+            }
+        }
+        if self.progress.window.starve(true) {
+            return None;
+        }
+        self.end_run(actions, text.len())
+    }
+
+    /**
+     * The token for text[start..end], or the INVALID token for it.
+     */
+    fn token(&self, rule: usize, start: usize, end: usize) -> Token<'t> {
+        let base = self.progress.window.base;
+        if rule == INVALID {
+            invalid_token(base + start, base + end)
+        } else {
+            let mut tok = Token::at(rule, self.text, base, start, end);
+            tok.invalid = invalid_within(self.invalid, start, end);
+            tok
+        }
+    }
+
+    /**
+     * Offer the matches at `start` to their actions, best first, until
+     * one does not reject its token. Returns that token, its length in
+     * the text, and what the action said (Truncate having been done).
+     */
+    fn choose<E, A>(&self, engine: &E, actions: &mut A, start: usize)
+        -> Option<(Token<'t>, usize, ActionResult)>
+        where E: MatchEngine + ?Sized,
+              A: Actions
+    {
+        // Usually the best match is taken, and there is no need to rank
+        // the others.
+        let best = engine.best_match()?;
+        if let Some(chosen) = self.offer(actions, best, start) {
+            return Some(chosen);
+        }
+        engine.ranked_matches().into_iter().skip(1).filter_map(|m| self.offer(actions, m, start)).next()
+    }
+
+    fn offer<A: Actions>(&self, actions: &mut A, m: MatchRecord, start: usize)
+        -> Option<(Token<'t>, usize, ActionResult)>
+    {
+        let tok = self.token(m.rule, start, start + m.len);
+        match actions.run(&tok) {
+            ActionResult::Reject => None,
+            ActionResult::Truncate(n) => {
+                // Cutting an invalid sequence in two would leave a piece
+                // that is neither valid nor invalid.
+                let len = match tok.text.char_indices().nth(n) {
+                    Some((offset, _)) => past_invalid(self.invalid, start + offset) - start,
+                    None => m.len,
+                };
+                if len == 0 {
+                    return None;
+                }
+                Some((self.token(m.rule, start, start + len), len, ActionResult::Accept))
+            }
+            result => Some((tok, m.len, result)),
+        }
+    }
+
+    /**
+     * Run the action for an UNMATCHED or INVALID token, and change the
+     * condition if it says to. Returns the token, unless it is skipped.
+     */
+    fn settle<A: Actions>(&self, actions: &mut A, rule: usize, start: usize, end: usize) -> Option<Token<'t>> {
+        let tok = self.token(rule, start, end);
+        let result = actions.run(&tok);
+        actions.follow(result);
+        if result == ActionResult::Skip { None } else { Some(tok) }
+    }
+
+    /**
+     * The token for the unmatched run up to `end`, if there is one and
+     * its action keeps it.
+     */
+    fn end_run<A: Actions>(&mut self, actions: &mut A, end: usize) -> Option<Token<'t>> {
+        match self.progress.run_start.take() {
+            Some(run_start) => self.settle(actions, UNMATCHED, run_start, end),
+            None => None,
+        }
+    }
+
+    fn stop(&mut self, error: TokenizeError) {
+        self.progress.error = Some(error);
+        self.progress.pos = self.text.len();
+        self.progress.run_start = None;
+    }
+}
@@ -0,0 +1,365 @@
+/*
+ * Tokenizing input read a piece at a time.
+ *
+ * We keep a buffer of the text read so far, and tokenize it as usual
+ * (with reinterp::Tokens, or retoken::Scan), except that they stop and
+ * ask for more whenever the engine runs into the end of the buffer
+ * with some rule still in the running (MatchEngine::hit_end()). A
+ * longer match might be waiting in the next chunk, and assertions like
+ * '$' and '\b' need to see what comes after. Only once no thread
+ * survives, or the input is exhausted, is a token committed. So the
+ * tokens are exactly the ones we would get from the whole input as a
+ * single string.
+ *
+ * Chunks can end in the middle of a UTF-8 sequence; those bytes wait
+ * for the rest of the character. Text before the current token is
+ * dropped from the buffer, except for the character just before it,
+ * which assertions need to see.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::str;
+use reinterp::{ConditionError, MatchEngine, NoMatchError, Token, Tokens, UnmatchedPolicy};
+use rescan::{Progress, Window};
+use retoken::TokenizeError;
+
+
+/**
+ * How much to read at a time.
+ */
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024;
+
+
+#[derive(Debug)]
+pub enum StreamError {
+    Io(io::Error),
+    /// No rule matches the input, under UnmatchedPolicy::Stop.
+    NoMatch(NoMatchError),
+    /// An action asked for a start condition the program does not have.
+    NoCondition(ConditionError),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StreamError::Io(ref e) => write!(f, "{}", e),
+            StreamError::NoMatch(ref e) => write!(f, "{}", e),
+            StreamError::NoCondition(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for StreamError {}
+
+impl From<io::Error> for StreamError {
+    fn from(e: io::Error) -> StreamError {
+        StreamError::Io(e)
+    }
+}
+
+impl From<NoMatchError> for StreamError {
+    fn from(e: NoMatchError) -> StreamError {
+        StreamError::NoMatch(e)
+    }
+}
+
+impl From<TokenizeError> for StreamError {
+    fn from(e: TokenizeError) -> StreamError {
+        match e {
+            TokenizeError::NoMatch(e) => StreamError::NoMatch(e),
+            TokenizeError::NoCondition(e) => StreamError::NoCondition(e),
+        }
+    }
+}
+
+
+/**
+ * A token that owns its text, since the buffer it was found in will
+ * not be around for long. start and end are byte offsets into the
+ * whole input, and so are the invalid spans (see reinterp::Token).
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnedToken {
+    pub rule: usize,
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+    pub invalid: Vec<(usize, usize)>,
+}
+
+impl<'t> From<Token<'t>> for OwnedToken {
+    fn from(tok: Token<'t>) -> OwnedToken {
+        OwnedToken {
+            rule: tok.rule,
+            start: tok.start,
+            end: tok.end,
+            text: tok.text.to_string(),
+            invalid: tok.invalid.to_vec(),
+        }
+    }
+}
+
+impl OwnedToken {
+    pub fn as_token(&self) -> Token<'_> {
+        Token { rule: self.rule, start: self.start, end: self.end, text: &self.text, invalid: &self.invalid }
+    }
+
+    pub fn is_unmatched(&self) -> bool {
+        self.as_token().is_unmatched()
+    }
+
+    pub fn is_invalid(&self) -> bool {
+        self.as_token().is_invalid()
+    }
+}
+
+
+/**
+ * The text read so far, from byte offset `base` of the input on.
+ */
+pub(crate) struct InputBuffer<R: Read> {
+    reader: R,
+    text: String,
+    base: usize,
+    incomplete: Vec<u8>,    // the start of a UTF-8 sequence cut off by a chunk
+    eof: bool,
+    chunk_size: usize,
+}
+
+impl<R: Read> InputBuffer<R> {
+    pub(crate) fn new(reader: R) -> InputBuffer<R> {
+        InputBuffer {
+            reader,
+            text: String::new(),
+            base: 0,
+            incomplete: vec![],
+            eof: false,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    pub(crate) fn text(&self) -> &str {
+        &self.text
+    }
+
+    fn window(&self) -> Window {
+        Window { base: self.base, partial: !self.eof, starved: false }
+    }
+
+    /**
+     * Where Tokens or Scan start on the buffer.
+     */
+    pub(crate) fn start(&self) -> Progress {
+        Progress::new(self.window())
+    }
+
+    /**
+     * Read another chunk. Text before the tokenizer's place in the
+     * buffer (`progress`) is no longer needed, bar one character, so it
+     * goes, and `progress` moves down to match.
+     */
+    pub(crate) fn fill(&mut self, progress: &mut Progress) -> io::Result<()> {
+        let keep = progress.needed_from();
+        let cut = self.text[..keep].char_indices().next_back().map_or(0, |(i, _)| i);
+        self.text.drain(..cut);
+        self.base += cut;
+        let read = self.read_chunk();
+        progress.shift(cut, self.window());
+        read
+    }
+
+    fn read_chunk(&mut self) -> io::Result<()> {
+        let mut chunk = vec![0; self.chunk_size];
+        let n = loop {
+            match self.reader.read(&mut chunk) {
+                Ok(n) => break n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+        if n == 0 {
+            self.eof = true;
+            if !self.incomplete.is_empty() {
+                return Err(invalid_utf8());
+            }
+            return Ok(());
+        }
+        self.incomplete.extend_from_slice(&chunk[..n]);
+        let valid = match str::from_utf8(&self.incomplete) {
+            Ok(s) => s.len(),
+            Err(e) => {
+                if e.error_len().is_some() {
+                    return Err(invalid_utf8());
+                }
+                e.valid_up_to()
+            }
+        };
+        self.text.push_str(str::from_utf8(&self.incomplete[..valid]).unwrap());
+        self.incomplete.drain(..valid);
+        Ok(())
+    }
+}
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "input is not valid UTF-8")
+}
+
+
+/**
+ * Iterator over the tokens of everything read from `reader`, as
+ * reinterp::Tokens would find them in the whole input. Iteration ends
+ * after the first error.
+ */
+pub struct StreamTokens<'e, E: 'e + MatchEngine + ?Sized, R: Read> {
+    engine: &'e mut E,
+    input: InputBuffer<R>,
+    progress: Progress,
+    policy: UnmatchedPolicy,
+    done: bool,
+}
+
+impl<'e, E: 'e + MatchEngine + ?Sized, R: Read> StreamTokens<'e, E, R> {
+    pub fn new(engine: &'e mut E, reader: R) -> StreamTokens<'e, E, R> {
+        let input = InputBuffer::new(reader);
+        let progress = input.start();
+        StreamTokens {
+            engine,
+            input,
+            progress,
+            policy: UnmatchedPolicy::default(),
+            done: false,
+        }
+    }
+
+    pub fn with_unmatched_policy(mut self, policy: UnmatchedPolicy) -> StreamTokens<'e, E, R> {
+        self.policy = policy;
+        self
+    }
+
+    /**
+     * Read this many bytes at a time.
+     */
+    pub fn with_chunk_size(mut self, size: usize) -> StreamTokens<'e, E, R> {
+        self.input.chunk_size = ::std::cmp::max(size, 1);
+        self
+    }
+}
+
+impl<'e, E: 'e + MatchEngine + ?Sized, R: Read> Iterator for StreamTokens<'e, E, R> {
+    type Item = Result<OwnedToken, StreamError>;
+
+    fn next(&mut self) -> Option<Result<OwnedToken, StreamError>> {
+        while !self.done {
+            let tok = {
+                let mut tokens = Tokens::new(&mut *self.engine, self.input.text())
+                    .with_unmatched_policy(self.policy)
+                    .resume(self.progress);
+                let tok = tokens.next().map(OwnedToken::from);
+                self.progress = tokens.progress();
+                tok
+            };
+            if tok.is_some() {
+                return tok.map(Ok);
+            }
+            if let Some(e) = self.progress.error() {
+                self.done = true;
+                return Some(Err(e.into()));
+            }
+            if !self.progress.starved() {
+                self.done = true;
+                break;
+            }
+            if let Err(e) = self.input.fill(&mut self.progress) {
+                self.done = true;
+                return Some(Err(e.into()));
+            }
+        }
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reinterp::NoMatchError;
+    use relazy::LazyDfa;
+    use retoken::Tokenizer;
+    use testutil::{compile, engines, RULES, TEXT};
+
+    fn streamed<E: MatchEngine + ?Sized>(engine: &mut E, text: &str, chunk_size: usize,
+                                         policy: UnmatchedPolicy) -> Vec<OwnedToken> {
+        StreamTokens::new(engine, text.as_bytes())
+            .with_chunk_size(chunk_size)
+            .with_unmatched_policy(policy)
+            .map(|tok| tok.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn every_chunk_size_gives_the_whole_string_tokens() {
+        // Without the catch-all '.', some of the text is unmatched.
+        for rules in &[RULES, &RULES[..RULES.len() - 1]] {
+            for mut engine in engines(rules) {
+                for &policy in &[UnmatchedPolicy::SkipChar, UnmatchedPolicy::Coalesce] {
+                    let whole: Vec<OwnedToken> = Tokens::new(&mut engine, TEXT)
+                        .with_unmatched_policy(policy)
+                        .map(OwnedToken::from)
+                        .collect();
+                    for chunk_size in 1..TEXT.len() + 2 {
+                        assert_eq!(streamed(&mut engine, TEXT, chunk_size, policy), whole,
+                                   "chunk size {}, {:?}", chunk_size, policy);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stopping_is_at_the_same_place() {
+        let text = "ab cd é?ef";
+        for mut engine in engines(&[r"\w+", r"\s+"]) {
+            for chunk_size in 1..text.len() + 1 {
+                let results: Vec<_> = StreamTokens::new(&mut engine, text.as_bytes())
+                    .with_chunk_size(chunk_size)
+                    .with_unmatched_policy(UnmatchedPolicy::Stop)
+                    .collect();
+                assert_eq!(results.len(), 6);
+                match results[5] {
+                    Err(StreamError::NoMatch(e)) => assert_eq!(e, NoMatchError { pos: 8 }),
+                    ref other => panic!("{:?}", other),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tokenizer_reads_the_same_tokens() {
+        let mut tokenizer = Tokenizer::new(LazyDfa::new(compile(RULES), vec![]));
+        for _ in RULES {
+            tokenizer.add_action(|seen: &mut Vec<(usize, usize, usize)>, tok: &Token| {
+                seen.push((tok.rule, tok.start, tok.end));
+            });
+        }
+        let mut whole = vec![];
+        tokenizer.apply(&mut whole, TEXT).unwrap();
+        let mut read = vec![];
+        tokenizer.apply_reader(&mut read, TEXT.as_bytes()).unwrap();
+        assert_eq!(read, whole);
+    }
+
+    #[test]
+    fn cut_characters_wait_for_the_rest() {
+        let mut engine = LazyDfa::new(compile(&[r"\p{Greek}+", "."]), vec![]);
+        let tokens = streamed(&mut engine, "αβγ!", 1, UnmatchedPolicy::SkipChar);
+        let texts: Vec<&str> = tokens.iter().map(|tok| &tok.text[..]).collect();
+        assert_eq!(texts, vec!["αβγ", "!"]);
+        // A sequence that the input ends in the middle of is an error.
+        let mut engine = LazyDfa::new(compile(&["."]), vec![]);
+        let results: Vec<_> = StreamTokens::new(&mut engine, &b"a\xce"[..]).with_chunk_size(1).collect();
+        assert!(matches!(results.last(), Some(Err(StreamError::Io(_)))), "{:?}", results);
+    }
+}
@@ -1,4 +1,7 @@
+use std::cmp::Ordering;
 use std::fmt;
+use std::sync::OnceLock;
+use unicode_tables;
 
 #[derive(Debug)]
 pub enum TermType {
@@ -7,9 +10,14 @@ pub enum TermType {
     Iteration,
     PositiveIteration,
     Optional,
+    Repetition { min: usize, max: Option<usize> },
     Atom(char, bool),
     CharClassTerm(CharClassData, bool),
-    AnyCharTerm,
+    AnyCharTerm(bool),
+    AssertionTerm(Assertion),
+    InvalidTerm,                // an invalid UTF-8 sequence (see rebytes.rs)
+    Empty,
+    TrailingContext,            // r/s, only ever at the root
 }
 
 #[derive(Debug)]
@@ -27,15 +35,15 @@ impl Term {
      */
     pub fn new(op: TermType, subs: Vec<Term>) -> Term {
         Term {
-            op: op,
-            subs: subs
+            op,
+            subs
         }
     }
 }
 
 impl fmt::Display for Term {
     fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
-        pretty_print(&self, 0)
+        pretty_print(self, 0)
     }
 }
 
@@ -57,6 +65,13 @@ fn print_label(t: &Term) {
         Iteration => { print!("FREE_ITERATION"); },
         PositiveIteration => { print!("POSITIVE_ITERATION"); },
         Optional => { print!("OPTIONAL"); },
+        Repetition { min, max } => {
+            match max {
+                Some(m) if m == min => print!("REPETITION {{{}}}", min),
+                Some(m) => print!("REPETITION {{{},{}}}", min, m),
+                None => print!("REPETITION {{{},}}", min),
+            }
+        },
         Atom(c, nocase) => { 
             print!("ATOM '{}'", c); 
             if nocase {
@@ -69,7 +84,16 @@ fn print_label(t: &Term) {
                 print!(" (?i)");
             }  
         },
-        AnyCharTerm => { print!("ANY_CHAR"); },
+        AnyCharTerm(dot_nl) => { 
+            print!("ANY_CHAR"); 
+            if dot_nl {
+                print!(" (?s)");
+            }
+        },
+        AssertionTerm(kind) => { print!("ASSERT {}", kind); },
+        InvalidTerm => { print!("INVALID"); },
+        Empty => { print!("EMPTY"); },
+        TrailingContext => { print!("TRAILING_CONTEXT"); },
     }
 }
 
@@ -77,7 +101,7 @@ fn print_label(t: &Term) {
 fn pretty_print(t: &Term, tab: usize) -> fmt::Result {
     tab_over(tab);
     print_label(t);
-    println!("");
+    println!();
     for sb in &t.subs {
         pretty_print(sb, tab + 4).unwrap();
     }
@@ -85,7 +109,7 @@ fn pretty_print(t: &Term, tab: usize) -> fmt::Result {
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CharClassData {
     positive: bool,
     ranges: Vec<CharClassPredicate>,
@@ -110,49 +134,149 @@ impl CharClassData {
     }
     
     pub fn matches(&self, ch: char) -> bool {
-        use self::CharClassPredicate::*;
+        self.matches_positive(ch) == self.positive
+    }
+
+    fn matches_positive(&self, ch: char) -> bool {
+        self.ranges.iter().any(|pred| pred.matches(ch))
+    }
+
+    /**
+     * The class closed under simple Unicode case folding, so that it can
+     * be matched case-insensitively without folding the input. For a
+     * negated class we close the positive part and then negate it, so
+     * '(?i)[^k]' rejects 'K' and the Kelvin sign as well.
+     * Only characters with other case forms can be added, so we just
+     * check each of those for a member of its orbit in the class.
+     */
+    pub fn case_fold(&self) -> CharClassData {
+        let mut extra: Vec<char> = vec![];
+        for &(ch, _) in unicode_tables::CASE_FOLDING_ORBITS {
+            if !self.matches_positive(ch) &&
+               case_orbit(ch).into_iter().any(|c| self.matches_positive(c)) {
+                extra.push(ch);
+            }
+        }
+        let mut ranges = self.ranges.clone();
+        let mut i = 0;
+        while i < extra.len() {
+            let mut j = i;
+            while j + 1 < extra.len() && extra[j + 1] as u32 == extra[j] as u32 + 1 {
+                j += 1;
+            }
+            ranges.push(if i == j {
+                CharClassPredicate::Individual(extra[i])
+            } else {
+                CharClassPredicate::Range(extra[i], extra[j])
+            });
+            i = j + 1;
+        }
+        CharClassData::new(self.positive, ranges)
+    }
+
+    /**
+     * The characters in the class, as sorted, disjoint, non-adjacent
+     * ranges.
+     */
+    pub fn char_ranges(&self) -> Vec<(char, char)> {
+        let mut ranges: Vec<(char, char)> = vec![];
         for pred in &self.ranges {
             match *pred {
-                Range(c1, c2) => {
-                    //println!("Range({}, {})", c1, c2);
-                    if ch >= c1 && ch <= c2 && self.positive {
-                         return true;
-                    }
-                }
-                Individual(c1) => {
-                    //println!("Individual({})", c1);
-                    if c1 == ch && self.positive {
-                        return true;
-                    }
+                CharClassPredicate::Range(lo, hi) => ranges.push((lo, hi)),
+                CharClassPredicate::Individual(ch) => ranges.push((ch, ch)),
+                CharClassPredicate::Named(ref nc) => ranges.extend(nc.char_ranges()),
+                CharClassPredicate::NotNamed(ref nc) => {
+                    ranges.extend(complement(&nc.char_ranges()));
                 }
-                Named(_) => {
-                    panic!("matches() unimplemented for Named");
+            }
+        }
+        let ranges = canonical(ranges);
+        if self.positive { ranges } else { complement(&ranges) }
+    }
+}
+
+/**
+ * Sort ranges, and merge the ones that overlap or touch.
+ */
+fn canonical(mut ranges: Vec<(char, char)>) -> Vec<(char, char)> {
+    ranges.retain(|&(lo, hi)| lo <= hi);
+    ranges.sort();
+    let mut merged: Vec<(char, char)> = vec![];
+    for (lo, hi) in ranges {
+        match merged.last_mut() {
+            Some(last) if lo as u32 <= last.1 as u32 + 1 => {
+                if hi > last.1 {
+                    last.1 = hi;
                 }
-            } 
+            }
+            _ => merged.push((lo, hi)),
+        }
+    }
+    merged
+}
+
+/**
+ * The characters not in canonical ranges. (Surrogates are not
+ * characters, so they are in neither.)
+ */
+fn complement(ranges: &[(char, char)]) -> Vec<(char, char)> {
+    let mut result = vec![];
+    let mut next: u32 = 0;
+    for &(lo, hi) in ranges {
+        if (lo as u32) > next {
+            push_chars(&mut result, next, lo as u32 - 1);
+        }
+        next = hi as u32 + 1;
+    }
+    push_chars(&mut result, next, ::std::char::MAX as u32);
+    result
+}
+
+/**
+ * Push the characters in lo..=hi, skipping any surrogates.
+ */
+fn push_chars(ranges: &mut Vec<(char, char)>, lo: u32, hi: u32) {
+    let pieces = [(lo, hi.min(0xD7FF)), (lo.max(0xE000), hi)];
+    for &(lo, hi) in &pieces {
+        if lo <= hi {
+            if let (Some(lo), Some(hi)) = (::std::char::from_u32(lo), ::std::char::from_u32(hi)) {
+                ranges.push((lo, hi));
+            }
         }
-        !self.positive
     }
-    
 }
 
 impl fmt::Display for CharClassData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         if !self.positive {
-            try!(write!(f, "NOT "));
+            write!(f, "NOT ")?;
         }
         for rng in &self.ranges {
-            try!(write!(f, "{} ", rng));
+            write!(f, "{} ", rng)?;
         }
         Ok(())
     }
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum CharClassPredicate {
     Range(char, char),
     Individual(char),
-    Named(String),
+    Named(NamedClass),
+    NotNamed(NamedClass),
+}
+
+impl CharClassPredicate {
+    pub fn matches(&self, ch: char) -> bool {
+        use self::CharClassPredicate::*;
+        match *self {
+            Range(c1, c2) => c1 <= ch && ch <= c2,
+            Individual(c1) => c1 == ch,
+            Named(nc) => nc.matches(ch),
+            NotNamed(nc) => !nc.matches(ch),
+        }
+    }
 }
 
 impl fmt::Display for CharClassPredicate {
@@ -165,12 +289,520 @@ impl fmt::Display for CharClassPredicate {
             Individual(c) => {
                 write!(f, "{}", c)
             }
-            Named(ref nm) => {
-                write!(f, "[:{}:]", nm)
+            Named(nc) => {
+                write!(f, "{}", nc)
+            }
+            NotNamed(NamedClass::Unicode(uc)) => {
+                write!(f, "\\P{{{}}}", uc.name())
+            }
+            NotNamed(nc) => {
+                write!(f, "[:^{}:]", nc.name())
+            }
+        }
+    }
+}
+
+
+/**
+ * The POSIX bracket expression classes, as in '[[:alpha:]]'.
+ * These are Unicode-aware, so e.g. [:alpha:] matches 'é' and 'ß',
+ * not just ASCII letters. The one exception is [:xdigit:], since hex
+ * digits are an ASCII notion anyway.
+ * [:word:] is not POSIX, but it is what '\w' stands for.
+ * Unicode general categories and scripts ('\p{Lu}', '\p{Greek}') are
+ * named classes too, though they have no '[:name:]' spelling.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NamedClass {
+    Alpha,
+    Digit,
+    Alnum,
+    Upper,
+    Lower,
+    Space,
+    Punct,
+    XDigit,
+    Cntrl,
+    Print,
+    Graph,
+    Blank,
+    Word,
+    Unicode(UnicodeClass),
+}
+
+impl NamedClass {
+
+    pub fn from_name(name: &str) -> Option<NamedClass> {
+        use self::NamedClass::*;
+        match name {
+            "alpha" => Some(Alpha),
+            "digit" => Some(Digit),
+            "alnum" => Some(Alnum),
+            "upper" => Some(Upper),
+            "lower" => Some(Lower),
+            "space" => Some(Space),
+            "punct" => Some(Punct),
+            "xdigit" => Some(XDigit),
+            "cntrl" => Some(Cntrl),
+            "print" => Some(Print),
+            "graph" => Some(Graph),
+            "blank" => Some(Blank),
+            "word" => Some(Word),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        use self::NamedClass::*;
+        match *self {
+            Alpha => "alpha",
+            Digit => "digit",
+            Alnum => "alnum",
+            Upper => "upper",
+            Lower => "lower",
+            Space => "space",
+            Punct => "punct",
+            XDigit => "xdigit",
+            Cntrl => "cntrl",
+            Print => "print",
+            Graph => "graph",
+            Blank => "blank",
+            Word => "word",
+            Unicode(uc) => uc.name(),
+        }
+    }
+
+    /**
+     * Mostly delegates to the Unicode predicates on char.
+     * [:digit:] is decimal digits (Nd) in any script.
+     * [:punct:] follows POSIX in lumping symbols (S) together with
+     * punctuation (P).
+     * [:word:] follows Unicode TR18: letters, marks, decimal digits and
+     * connector punctuation like '_'.
+     */
+    pub fn matches(&self, ch: char) -> bool {
+        use self::NamedClass::*;
+        match *self {
+            Alpha => ch.is_alphabetic(),
+            Digit => in_general_category("Nd", ch),
+            Alnum => ch.is_alphanumeric(),
+            Upper => ch.is_uppercase(),
+            Lower => ch.is_lowercase(),
+            Space => ch.is_whitespace(),
+            Punct => in_general_category("P", ch) || in_general_category("S", ch),
+            XDigit => ch.is_ascii_hexdigit(),
+            Cntrl => ch.is_control(),
+            Print => !ch.is_control(),
+            Graph => !ch.is_control() && !ch.is_whitespace(),
+            Blank => {
+                ch == '\t' || (ch.is_whitespace() && !is_line_break(ch))
+            }
+            Word => {
+                ch.is_alphabetic() ||
+                in_general_category("M", ch) ||
+                in_general_category("Nd", ch) ||
+                in_general_category("Pc", ch)
+            }
+            Unicode(uc) => uc.matches(ch),
+        }
+    }
+}
+
+impl NamedClass {
+    /**
+     * The characters in the class, as for CharClassData::char_ranges().
+     * Classes defined by general categories come straight from their
+     * tables. The others have to test every character, so we only do
+     * that once per class.
+     */
+    pub fn char_ranges(&self) -> Vec<(char, char)> {
+        static RANGES: [OnceLock<Vec<(char, char)>>; 13] = [const { OnceLock::new() }; 13];
+        use self::NamedClass::*;
+        let i = match *self {
+            Alpha => 0,
+            Digit => 1,
+            Alnum => 2,
+            Upper => 3,
+            Lower => 4,
+            Space => 5,
+            Punct => 6,
+            XDigit => 7,
+            Cntrl => 8,
+            Print => 9,
+            Graph => 10,
+            Blank => 11,
+            Word => 12,
+            Unicode(uc) => return canonical(uc.ranges.to_vec()),
+        };
+        RANGES[i].get_or_init(|| self.compute_ranges()).clone()
+    }
+
+    fn compute_ranges(&self) -> Vec<(char, char)> {
+        use self::NamedClass::*;
+        let gc = general_category_ranges;
+        match *self {
+            Digit => gc("Nd").to_vec(),
+            Punct => canonical([gc("P"), gc("S")].concat()),
+            Word => {
+                let mut ranges = ranges_where(|ch| ch.is_alphabetic());
+                for name in &["M", "Nd", "Pc"] {
+                    ranges.extend_from_slice(gc(name));
+                }
+                canonical(ranges)
+            }
+            _ => ranges_where(|ch| self.matches(ch)),
+        }
+    }
+}
+
+/**
+ * The characters for which `pred` holds, found by testing them all.
+ */
+fn ranges_where<F: Fn(char) -> bool>(pred: F) -> Vec<(char, char)> {
+    let mut ranges: Vec<(char, char)> = vec![];
+    for ch in (0..0x11_0000).filter_map(::std::char::from_u32) {
+        if !pred(ch) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.1 as u32 + 1 == ch as u32 => last.1 = ch,
+            _ => ranges.push((ch, ch)),
+        }
+    }
+    ranges
+}
+
+impl fmt::Display for NamedClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NamedClass::Unicode(uc) => write!(f, "\\p{{{}}}", uc.name()),
+            _ => write!(f, "[:{}:]", self.name()),
+        }
+    }
+}
+
+
+/**
+ * A Unicode general category or script, backed by the generated
+ * tables in unicode_tables.rs.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnicodeClass {
+    name: &'static str,
+    ranges: &'static [(char, char)],
+}
+
+impl UnicodeClass {
+
+    /**
+     * Look up a class the way '\p{...}' spells it: 'L', 'Lu',
+     * 'Uppercase_Letter', 'Greek', 'Grek', or explicitly qualified as
+     * 'gc=Lu', 'General_Category=Lu', 'sc=Greek' or 'Script=Greek'.
+     * Names are matched loosely, ignoring case, '_', '-' and spaces.
+     * Bare names are tried as general categories before scripts.
+     */
+    pub fn from_name(name: &str) -> Option<UnicodeClass> {
+        let gc = || (unicode_tables::GENERAL_CATEGORY, 
+                     unicode_tables::GENERAL_CATEGORY_ALIASES);
+        let sc = || (unicode_tables::SCRIPT, unicode_tables::SCRIPT_ALIASES);
+        match name.find('=') {
+            Some(i) => {
+                let (prop, value) = (normalize_name(&name[..i]), &name[i + 1..]);
+                match prop.as_str() {
+                    "gc" | "generalcategory" => lookup_class(gc(), value),
+                    "sc" | "script" => lookup_class(sc(), value),
+                    _ => None,
+                }
             }
+            None => lookup_class(gc(), name).or_else(|| lookup_class(sc(), name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn matches(&self, ch: char) -> bool {
+        in_ranges(self.ranges, ch)
+    }
+}
+
+type ClassTable = &'static [(&'static str, &'static [(char, char)])];
+type AliasTable = &'static [(&'static str, usize)];
+
+fn lookup_class(tables: (ClassTable, AliasTable), name: &str) -> Option<UnicodeClass> {
+    let (classes, aliases) = tables;
+    let key = normalize_name(name);
+    aliases.binary_search_by(|&(alias, _)| alias.cmp(&key))
+           .ok()
+           .map(|i| {
+               let (name, ranges) = classes[aliases[i].1];
+               UnicodeClass { name, ranges }
+           })
+}
+
+fn normalize_name(name: &str) -> String {
+    name.chars()
+        .filter(|&c| c != '_' && c != '-' && c != ' ')
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+/**
+ * All the characters equivalent to ch under simple case folding, ch
+ * included, e.g. ['K', 'k', '\u{212A}'] for 'k'. Characters without
+ * other case forms give a one-element orbit.
+ */
+pub fn case_orbit(ch: char) -> Vec<char> {
+    let table = unicode_tables::CASE_FOLDING_ORBITS;
+    let next = |c: char| {
+        table.binary_search_by(|&(k, _)| k.cmp(&c)).ok().map(|i| table[i].1)
+    };
+    let mut orbit = vec![ch];
+    let mut c = ch;
+    while let Some(n) = next(c) {
+        if n == ch {
+            break;
         }
+        orbit.push(n);
+        c = n;
     }
+    orbit
 }
 
+/**
+ * Membership in a general category, by its canonical (short) name.
+ */
+fn in_general_category(name: &str, ch: char) -> bool {
+    in_ranges(general_category_ranges(name), ch)
+}
 
+/**
+ * The ranges of a general category, by its canonical (short) name.
+ */
+fn general_category_ranges(name: &str) -> &'static [(char, char)] {
+    let table = unicode_tables::GENERAL_CATEGORY;
+    match table.binary_search_by(|&(nm, _)| nm.cmp(name)) {
+        Ok(i) => table[i].1,
+        Err(_) => &[],
+    }
+}
 
+fn in_ranges(ranges: &[(char, char)], ch: char) -> bool {
+    ranges.binary_search_by(|&(lo, hi)| {
+        if hi < ch {
+            Ordering::Less
+        } else if lo > ch {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }).is_ok()
+}
+
+/**
+ * Vertical whitespace, i.e. the whitespace characters that [:blank:]
+ * leaves out.
+ */
+fn is_line_break(ch: char) -> bool {
+    matches!(ch, '\n' | '\u{0B}' | '\u{0C}' | '\r' | '\u{85}' | '\u{2028}' | '\u{2029}')
+}
+
+
+/**
+ * Zero-width assertions. Like CharClassData, these are shared between
+ * the term and the instruction that implements it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assertion {
+    StartText,
+    EndText,
+    StartLine,
+    EndLine,
+    WordBoundary,
+    NotWordBoundary,
+}
+
+impl Assertion {
+
+    /**
+     * Does the assertion hold between `prev` and `next`? These are the
+     * characters on either side of the position being tested, with None
+     * standing for the start or end of the text.
+     */
+    pub fn holds(&self, prev: Option<char>, next: Option<char>) -> bool {
+        use self::Assertion::*;
+        let is_word = |c: Option<char>| c.is_some_and(|c| NamedClass::Word.matches(c));
+        match *self {
+            StartText => prev.is_none(),
+            EndText => next.is_none(),
+            StartLine => prev.is_none() || prev == Some('\n'),
+            EndLine => next.is_none() || next == Some('\n'),
+            WordBoundary => is_word(prev) != is_word(next),
+            NotWordBoundary => is_word(prev) == is_word(next),
+        }
+    }
+
+    /**
+     * \b and \B, the assertions that care about word characters.
+     */
+    pub fn is_word_boundary(&self) -> bool {
+        matches!(*self, Assertion::WordBoundary | Assertion::NotWordBoundary)
+    }
+}
+
+impl fmt::Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Assertion::*;
+        let name = match *self {
+            StartText => "start_text",
+            EndText => "end_text",
+            StartLine => "start_line",
+            EndLine => "end_line",
+            WordBoundary => "word_boundary",
+            NotWordBoundary => "not_word_boundary",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::matches_whole;
+
+    #[test]
+    fn unicode_classes() {
+        // (name, a member, a non-member), as the generated tables have them.
+        let cases = [
+            ("Lu", 'Σ', 'σ'),
+            ("Uppercase_Letter", 'A', 'a'),
+            ("gc=Nd", '\u{660}', 'x'),
+            ("Nl", '\u{216B}', '1'),
+            ("L", '\u{16EA0}', '1'),
+            ("LC", 'a', '\u{10940}'),
+            ("Cn", '\u{378}', 'a'),
+            ("Co", '\u{E000}', 'a'),
+            ("Cf", '\u{AD}', '-'),
+            ("Mn", '\u{301}', 'e'),
+            ("So", '\u{1F600}', ':'),
+            ("Cc", '\0', ' '),
+            ("Greek", 'λ', 'l'),
+            ("sc=Grek", 'Ω', 'O'),
+            ("Script=Han", '中', 'な'),
+            ("Hiragana", 'ぁ', 'ァ'),
+            ("Katakana", 'ァ', 'ぁ'),
+            ("Common", '\u{1F600}', 'a'),
+            ("Inherited", '\u{301}', 'a'),
+            ("Unknown", '\u{10FFFF}', 'a'),
+            // New in Unicode 17.0.
+            ("Sidetic", '\u{10940}', 'a'),
+            ("Tai Yo", '\u{1E6C0}', 'a'),
+            ("beria-erfe", '\u{16EA0}', 'a'),
+            ("Tolong_Siki", '\u{11DB0}', 'a'),
+        ];
+        for &(name, member, other) in cases.iter() {
+            let uc = UnicodeClass::from_name(name).unwrap();
+            assert!(uc.matches(member), "{:?} should be in {}", member, name);
+            assert!(!uc.matches(other), "{:?} should not be in {}", other, name);
+        }
+    }
+
+    #[test]
+    fn unicode_class_names() {
+        let name = |n| UnicodeClass::from_name(n).map(|uc| uc.name());
+        assert_eq!(name("uppercase letter"), Some("Lu"));
+        assert_eq!(name("General_Category=digit"), Some("Nd"));
+        assert_eq!(name("grek"), Some("Greek"));
+        assert_eq!(name("Qaac"), Some("Coptic"));
+        // Only surrogates are Cs, and they are not chars.
+        assert_eq!(name("Surrogate"), Some("Cs"));
+        // No character has the script Katakana_Or_Hiragana.
+        assert_eq!(name("Hrkt"), None);
+        assert_eq!(name("sc=Lu"), None);
+        assert_eq!(name("Klingon"), None);
+    }
+
+    #[test]
+    fn case_orbits() {
+        let orbit = |ch| {
+            let mut orbit = case_orbit(ch);
+            orbit.sort();
+            orbit
+        };
+        assert_eq!(orbit('a'), ['A', 'a']);
+        assert_eq!(orbit('k'), ['K', 'k', '\u{212A}']);
+        assert_eq!(orbit('\u{212A}'), ['K', 'k', '\u{212A}']);
+        assert_eq!(orbit('S'), ['S', 's', '\u{17F}']);
+        assert_eq!(orbit('σ'), ['Σ', 'ς', 'σ']);
+        assert_eq!(orbit('ß'), ['ß', '\u{1E9E}']);
+        assert_eq!(orbit('1'), ['1']);
+        // The Turkish i's only fold to I and i under the Turkic rules.
+        assert_eq!(orbit('I'), ['I', 'i']);
+        assert_eq!(orbit('\u{130}'), ['\u{130}']);
+        assert_eq!(orbit('\u{131}'), ['\u{131}']);
+    }
+
+    #[test]
+    fn case_insensitive_matching() {
+        let cases = [
+            ("(?i)k", "\u{212A}", true),
+            ("(?i)\u{212A}", "k", true),
+            ("(?i)[a-z]+", "Kelvin\u{212A}", true),
+            ("(?i)[^k]", "\u{212A}", false),
+            ("(?i)[^k]", "K", false),
+            ("(?i)σ+", "Σσς", true),
+            ("(?i)straße", "STRA\u{1E9E}E", true),
+            // Simple folding only: one character never matches two.
+            ("(?i)straße", "STRASSE", false),
+            ("(?i)i", "\u{131}", false),
+            ("(?i)[[:lower:]]", "A", true),
+            (r"(?i)\p{Ll}", "A", true),
+        ];
+        for &(rule, text, want) in cases.iter() {
+            assert_eq!(matches_whole(rule, text), want, "{:?} on {:?}", rule, text);
+        }
+    }
+
+    #[test]
+    fn posix_classes() {
+        let cases = [
+            ("alpha", "aZéßλ", "1_ -"),
+            ("digit", "09٣", "a²½"),
+            ("alnum", "a9é٣", "_ -"),
+            ("upper", "AÉΣ", "aé1"),
+            ("lower", "aéσß", "AÉ1"),
+            ("space", " \t\n\u{a0}\u{2028}", "a_"),
+            ("punct", "!-_$+«", "a1 "),
+            ("xdigit", "09afAF", "gG٣"),
+            ("cntrl", "\0\n\u{7f}\u{85}", "a \u{a0}"),
+            ("print", "a \u{a0}é", "\0\n"),
+            ("graph", "a!é", " \n\0"),
+            ("blank", " \t\u{a0}", "\n\r\u{2028}a"),
+            ("word", "aé9٣_\u{301}", "-! "),
+        ];
+        for &(name, members, others) in cases.iter() {
+            let nc = NamedClass::from_name(name).unwrap();
+            assert_eq!(nc.name(), name);
+            for ch in members.chars() {
+                assert!(nc.matches(ch), "{:?} should be in [:{}:]", ch, name);
+            }
+            for ch in others.chars() {
+                assert!(!nc.matches(ch), "{:?} should not be in [:{}:]", ch, name);
+            }
+        }
+        assert_eq!(NamedClass::from_name("Alpha"), None);
+    }
+
+    #[test]
+    fn posix_classes_in_brackets() {
+        assert!(matches_whole("[[:digit:][:upper:]_]+", "A1_B2"));
+        assert!(!matches_whole("[[:digit:][:upper:]_]+", "A1b"));
+        assert!(matches_whole("[[:^space:]]+", "no-spaces"));
+        assert!(!matches_whole("[[:^space:]]+", "a b"));
+        assert!(matches_whole("[^[:alpha:]]", "1"));
+        assert!(!matches_whole("[^[:alpha:]]", "é"));
+        // Without the colons, '[' is just a character in the class.
+        assert!(matches_whole("[[a]+", "[a["));
+    }
+}
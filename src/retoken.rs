@@ -0,0 +1,674 @@
+/*
+ * A tokenizer is a match engine plus an action for each rule.
+ *
+ * The engines can run actions themselves (MatchEngine::apply), but those
+ * are plain fn(&str) pointers, so any state they keep has to be global.
+ * Here the actions are closures over a user supplied context, which is
+ * passed to every action along with the token, so they can collect
+ * tokens, count things, or look words up in a dictionary.
+ *
+ * Actions also get a say in what happens next, the way flex actions do
+ * with REJECT, yyless() and BEGIN: see ActionResult.
+ *
+ * The tokenizer keeps a stack of start conditions (see
+ * reprog::StartCondition), and the one on top decides which rules are
+ * active. It starts out as just INITIAL. The engines themselves only
+ * know the current condition (MatchEngine::set_start_condition), so
+ * pushing and popping conditions is only available through a Tokenizer.
+ *
+ * Text that no rule matches (or where every action rejects its token)
+ * is handled according to an UnmatchedPolicy, and can have an action of
+ * its own. So can invalid UTF-8, when tokenizing bytes (see rebytes.rs).
+ *
+ * Under UnmatchedPolicy::Coalesce, we only know that a run of unmatched
+ * text has ended once some action accepts its token, so that action
+ * runs before the run's. The start condition changes they ask for are
+ * still made in the order of the text, the run's first, and the run's
+ * token still comes out first.
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+use reinterp::{ConditionError, MatchEngine, NoMatchError, Token, TokenizerAction, UnmatchedPolicy};
+use reprog::INITIAL;
+use rescan::{Actions, Progress, Scanner};
+use restream::{InputBuffer, StreamError};
+use rebytes::{decode, BytesError, InvalidUtf8};
+
+
+/**
+ * What an action wants done with its token.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionResult {
+    /// Keep the token. Actions that return () mean this.
+    Accept,
+    /// Consume the token's text, but do not emit it (e.g. whitespace).
+    Skip,
+    /// Pretend this rule did not match here, and go on to the next
+    /// best match at the same position, as flex's REJECT does.
+    Reject,
+    /// Keep only the first n characters of the token, and scan the rest
+    /// again, as flex's yyless(n) does. Keeping no characters at all
+    /// would leave us where we started, so Truncate(0) is a Reject.
+    Truncate(usize),
+    /// Keep the token, then replace the current start condition with
+    /// this one, as flex's BEGIN does.
+    Begin(usize),
+    /// Keep the token, then push this start condition. The stack is the
+    /// Tokenizer's: an engine on its own has no Push or Pop.
+    Push(usize),
+    /// Keep the token, then go back to the previous start condition.
+    Pop,
+}
+
+impl From<()> for ActionResult {
+    fn from(_: ()) -> ActionResult {
+        ActionResult::Accept
+    }
+}
+
+
+/**
+ * Why a Tokenizer stopped before the end of the text.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizeError {
+    /// No rule matches the text, under UnmatchedPolicy::Stop.
+    NoMatch(NoMatchError),
+    /// An action asked for a start condition the program does not have.
+    NoCondition(ConditionError),
+}
+
+impl fmt::Display for TokenizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TokenizeError::NoMatch(ref e) => write!(f, "{}", e),
+            TokenizeError::NoCondition(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for TokenizeError {}
+
+impl From<NoMatchError> for TokenizeError {
+    fn from(e: NoMatchError) -> TokenizeError {
+        TokenizeError::NoMatch(e)
+    }
+}
+
+impl From<ConditionError> for TokenizeError {
+    fn from(e: ConditionError) -> TokenizeError {
+        TokenizeError::NoCondition(e)
+    }
+}
+
+
+/**
+ * Something to run when a rule matches. Any FnMut(&mut Ctx, &Token)
+ * closure will do, returning either an ActionResult or nothing. So will
+ * the engines' TokenizerAction functions, which just get the token's
+ * text and ignore the context.
+ */
+pub trait Action<Ctx> {
+    fn run(&mut self, ctx: &mut Ctx, tok: &Token) -> ActionResult;
+}
+
+impl<Ctx, R, F> Action<Ctx> for F
+    where R: Into<ActionResult>,
+          F: FnMut(&mut Ctx, &Token) -> R
+{
+    fn run(&mut self, ctx: &mut Ctx, tok: &Token) -> ActionResult {
+        self(ctx, tok).into()
+    }
+}
+
+impl<Ctx> Action<Ctx> for TokenizerAction {
+    fn run(&mut self, _ctx: &mut Ctx, tok: &Token) -> ActionResult {
+        self(tok.text);
+        ActionResult::Accept
+    }
+}
+
+
+pub struct Tokenizer<E: MatchEngine, Ctx> {
+    engine: E,
+    handlers: Handlers<Ctx>,
+    policy: UnmatchedPolicy,
+    invalid: InvalidUtf8,
+}
+
+/**
+ * The actions, and the start condition stack they change. These are
+ * kept apart from the engine, which a Scan needs at the same time.
+ */
+struct Handlers<Ctx> {
+    actions: Vec<Box<dyn Action<Ctx>>>,
+    unmatched: Option<Box<dyn Action<Ctx>>>,
+    conditions: Vec<usize>,     // never empty
+}
+
+impl<Ctx> Handlers<Ctx> {
+    fn condition(&self) -> usize {
+        *self.conditions.last().unwrap()
+    }
+
+    fn begin(&mut self, sc: usize) {
+        *self.conditions.last_mut().unwrap() = sc;
+    }
+
+    fn pop_condition(&mut self) -> usize {
+        if self.conditions.len() > 1 {
+            self.conditions.pop();
+        }
+        self.condition()
+    }
+}
+
+impl<E: MatchEngine, Ctx> Tokenizer<E, Ctx> {
+
+    /**
+     * The engine's own actions are not used. Add one here for each rule
+     * instead, in rule order.
+     */
+    pub fn new(engine: E) -> Tokenizer<E, Ctx> {
+        Tokenizer {
+            engine,
+            handlers: Handlers {
+                actions: vec![],
+                unmatched: None,
+                conditions: vec![INITIAL],
+            },
+            policy: UnmatchedPolicy::default(),
+            invalid: InvalidUtf8::default(),
+        }
+    }
+
+    /**
+     * Set the action for the next rule: the first call is for rule 0,
+     * and so on.
+     */
+    pub fn add_action<A: Action<Ctx> + 'static>(&mut self, action: A) {
+        self.handlers.actions.push(Box::new(action));
+    }
+
+    pub fn set_unmatched_policy(&mut self, policy: UnmatchedPolicy) {
+        self.policy = policy;
+    }
+
+    /**
+     * Set the action for unmatched text (see reinterp::UNMATCHED), and
+     * for invalid UTF-8 (reinterp::INVALID). It can Skip the text, or
+     * change the start condition; anything else keeps the token.
+     * Without one, unmatched tokens are just kept.
+     */
+    pub fn set_unmatched_action<A: Action<Ctx> + 'static>(&mut self, action: A) {
+        self.handlers.unmatched = Some(Box::new(action));
+    }
+
+    /**
+     * What apply_bytes() does with invalid UTF-8.
+     */
+    pub fn set_invalid_utf8(&mut self, invalid: InvalidUtf8) {
+        self.invalid = invalid;
+    }
+
+    pub fn engine(&mut self) -> &mut E {
+        &mut self.engine
+    }
+
+    /**
+     * The start condition on top of the stack.
+     */
+    pub fn condition(&self) -> usize {
+        self.handlers.condition()
+    }
+
+    /**
+     * Replace the start condition on top of the stack.
+     */
+    pub fn begin(&mut self, sc: usize) {
+        self.handlers.begin(sc);
+    }
+
+    pub fn push_condition(&mut self, sc: usize) {
+        self.handlers.conditions.push(sc);
+    }
+
+    /**
+     * Go back to the start condition we were in before the last push.
+     * Popping the bottom of the stack just leaves it there.
+     */
+    pub fn pop_condition(&mut self) -> usize {
+        self.handlers.pop_condition()
+    }
+
+    /**
+     * Tokenize text lazily, running actions as we go, and yielding the
+     * tokens they accept.
+     */
+    pub fn tokens<'a, 't>(&'a mut self, ctx: &'a mut Ctx, text: &'t str)
+        -> Scan<'a, 't, E, Ctx>
+    {
+        let mut scanner = Scanner::new(text);
+        scanner.set_policy(self.policy);
+        Scan { tokenizer: self, ctx, scanner }
+    }
+
+    /**
+     * Run the actions over the whole of text. Rules without an action
+     * accept their tokens silently. Fails under UnmatchedPolicy::Stop,
+     * or if an action switches to a start condition that does not
+     * exist.
+     */
+    pub fn apply(&mut self, ctx: &mut Ctx, text: &str) -> Result<(), TokenizeError> {
+        let mut scan = self.tokens(ctx, text);
+        for _ in &mut scan {}
+        match scan.error() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /**
+     * Run the actions over everything read from `reader`, a chunk at a
+     * time (see restream.rs). The tokens are the ones apply() would
+     * find in the whole input, with offsets into the whole input.
+     */
+    pub fn apply_reader<R: Read>(&mut self, ctx: &mut Ctx, reader: R) -> Result<(), StreamError> {
+        let mut input = InputBuffer::new(reader);
+        let mut progress = input.start();
+        loop {
+            progress = {
+                let mut scan = self.tokens(ctx, input.text()).resume(progress);
+                for _ in &mut scan {}
+                scan.progress()
+            };
+            if let Some(e) = progress.error() {
+                return Err(e.into());
+            }
+            if !progress.starved() {
+                return Ok(());
+            }
+            input.fill(&mut progress)?;
+        }
+    }
+
+    /**
+     * Run the actions over bytes that may not be valid UTF-8, which is
+     * dealt with as set_invalid_utf8() said. Token offsets are into the
+     * bytes, except with InvalidUtf8::Replace.
+     */
+    pub fn apply_bytes(&mut self, ctx: &mut Ctx, bytes: &[u8]) -> Result<(), BytesError> {
+        let input = decode(bytes, self.invalid)?;
+        let mut scan = self.tokens(ctx, &input.text).with_invalid(&input.invalid);
+        for _ in &mut scan {}
+        match scan.error() {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+
+/**
+ * A Tokenizer's actions at work, with the context they get.
+ */
+struct Running<'a, Ctx: 'a> {
+    handlers: &'a mut Handlers<Ctx>,
+    ctx: &'a mut Ctx,
+}
+
+impl<'a, Ctx: 'a> Actions for Running<'a, Ctx> {
+    fn condition(&self) -> Option<usize> {
+        Some(self.handlers.condition())
+    }
+
+    fn run(&mut self, tok: &Token) -> ActionResult {
+        let action = if tok.is_unmatched() || tok.is_invalid() {
+            self.handlers.unmatched.as_mut()
+        } else {
+            self.handlers.actions.get_mut(tok.rule)
+        };
+        match action {
+            Some(action) => action.run(self.ctx, tok),
+            None => ActionResult::Accept,
+        }
+    }
+
+    fn follow(&mut self, result: ActionResult) {
+        match result {
+            ActionResult::Begin(sc) => self.handlers.begin(sc),
+            ActionResult::Push(sc) => self.handlers.conditions.push(sc),
+            ActionResult::Pop => { self.handlers.pop_condition(); }
+            _ => {}
+        }
+    }
+}
+
+
+/**
+ * Iterator over the tokens accepted by a Tokenizer's actions. At each
+ * position, the candidate matches are offered to their actions best
+ * first, until one does not reject its token. If they all do, or there
+ * are none, the text there is unmatched, as for reinterp::Tokens.
+ */
+pub struct Scan<'a, 't, E: 'a + MatchEngine, Ctx: 'a> {
+    tokenizer: &'a mut Tokenizer<E, Ctx>,
+    ctx: &'a mut Ctx,
+    scanner: Scanner<'t>,
+}
+
+impl<'a, 't, E: 'a + MatchEngine, Ctx: 'a> Scan<'a, 't, E, Ctx> {
+    /**
+     * Why we stopped, if we stopped early: at text no rule matches, or
+     * in a start condition that does not exist.
+     */
+    pub fn error(&self) -> Option<TokenizeError> {
+        self.scanner.error()
+    }
+
+    /**
+     * The invalid sequences in the text (see rebytes::decode()).
+     */
+    pub(crate) fn with_invalid(mut self, invalid: &'t [(usize, usize)]) -> Scan<'a, 't, E, Ctx> {
+        self.scanner.set_invalid(invalid);
+        self
+    }
+
+    /**
+     * Carry on from where another Scan over the same text got to.
+     */
+    pub(crate) fn resume(mut self, progress: Progress) -> Scan<'a, 't, E, Ctx> {
+        self.scanner.resume(progress);
+        self
+    }
+
+    pub(crate) fn progress(&self) -> Progress {
+        self.scanner.progress()
+    }
+}
+
+impl<'a, 't, E: 'a + MatchEngine, Ctx: 'a> Iterator for Scan<'a, 't, E, Ctx> {
+    type Item = Token<'t>;
+
+    fn next(&mut self) -> Option<Token<'t>> {
+        let tokenizer = &mut *self.tokenizer;
+        let mut running = Running { handlers: &mut tokenizer.handlers, ctx: &mut *self.ctx };
+        self.scanner.next(&mut tokenizer.engine, &mut running)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reinterp::{Tokens, UNMATCHED};
+    use relazy::LazyDfa;
+    use reparse::parse;
+    use reprog::Program;
+    use retrans::RegexTranslator;
+    use testutil::{compile, engines_for};
+
+    type Seen = Vec<(usize, String)>;
+
+    fn tokenizer(rules: &[&str]) -> Tokenizer<LazyDfa, Seen> {
+        Tokenizer::new(LazyDfa::new(compile(rules), vec![]))
+    }
+
+    /**
+     * The (rule, text) of the tokens scan yields.
+     */
+    fn scan(tokenizer: &mut Tokenizer<LazyDfa, Seen>, text: &str) -> Seen {
+        let mut seen = vec![];
+        tokenizer.tokens(&mut seen, text).map(|tok| (tok.rule, tok.text.to_string())).collect()
+    }
+
+    fn owned(tokens: &[(usize, &str)]) -> Seen {
+        tokens.iter().map(|&(rule, text)| (rule, text.to_string())).collect()
+    }
+
+    #[test]
+    fn skip_consumes_without_emitting() {
+        let mut t = tokenizer(&[r"\w+", r"\s+"]);
+        t.add_action(|_: &mut Seen, _: &Token| ActionResult::Accept);
+        t.add_action(|_: &mut Seen, _: &Token| ActionResult::Skip);
+        assert_eq!(scan(&mut t, "ab  cd "), owned(&[(0, "ab"), (0, "cd")]));
+    }
+
+    #[test]
+    fn reject_falls_back_to_the_next_best_match() {
+        let mut t = tokenizer(&["[a-z]+", "[a-z]", "x"]);
+        t.add_action(|seen: &mut Seen, tok: &Token| {
+            seen.push((tok.rule, tok.text.to_string()));
+            if tok.text.contains('x') { ActionResult::Reject } else { ActionResult::Accept }
+        });
+        t.add_action(|seen: &mut Seen, tok: &Token| {
+            seen.push((tok.rule, tok.text.to_string()));
+            if tok.text == "x" { ActionResult::Reject } else { ActionResult::Accept }
+        });
+        let mut seen = vec![];
+        let found: Seen = t.tokens(&mut seen, "axb")
+            .map(|tok| (tok.rule, tok.text.to_string()))
+            .collect();
+        assert_eq!(found, owned(&[(0, "a"), (2, "x"), (0, "b")]));
+        // Every (rule, length) match is a candidate, longest first, then
+        // lowest rule. Rule 2 has no action, so it accepts.
+        assert_eq!(seen, owned(&[(0, "axb"), (0, "ax"), (0, "a"),
+                                 (0, "xb"), (0, "x"), (1, "x"), (0, "b")]));
+    }
+
+    #[test]
+    fn rejecting_everything_leaves_the_text_unmatched() {
+        let mut t = tokenizer(&["a"]);
+        t.add_action(|_: &mut Seen, _: &Token| ActionResult::Reject);
+        assert_eq!(scan(&mut t, "ab"), owned(&[(UNMATCHED, "a"), (UNMATCHED, "b")]));
+    }
+
+    #[test]
+    fn truncate_keeps_characters_and_rescans_the_rest() {
+        let mut t = tokenizer(&[r"\w+", r"\W"]);
+        t.add_action(|_: &mut Seen, tok: &Token| {
+            if tok.text.chars().count() > 2 { ActionResult::Truncate(2) } else { ActionResult::Accept }
+        });
+        assert_eq!(scan(&mut t, "abcde éèàù"),
+                   owned(&[(0, "ab"), (0, "cd"), (0, "e"), (1, " "), (0, "éè"), (0, "àù")]));
+
+        // Truncating to the whole length or more keeps the token.
+        let mut t = tokenizer(&[r"\w+"]);
+        t.add_action(|_: &mut Seen, _: &Token| ActionResult::Truncate(9));
+        assert_eq!(scan(&mut t, "abc"), owned(&[(0, "abc")]));
+    }
+
+    #[test]
+    fn truncate_to_nothing_is_a_reject() {
+        let mut t = tokenizer(&[r"\w+", r"\w"]);
+        t.add_action(|_: &mut Seen, _: &Token| ActionResult::Truncate(0));
+        assert_eq!(scan(&mut t, "ab"), owned(&[(1, "a"), (1, "b")]));
+    }
+
+    #[test]
+    fn truncate_does_not_split_an_invalid_sequence() {
+        // Where truncating the token at 0 leaves the rest to rescan. The
+        // 0xe2 0x82 at 2 is one invalid sequence, two bytes long, so a
+        // cut inside it is moved to its end.
+        fn rescan_from(n: usize) -> Vec<usize> {
+            let mut t = Tokenizer::new(LazyDfa::new(compile(&[r"([a-z]|\I)+"]), vec![]));
+            t.set_invalid_utf8(InvalidUtf8::Token);
+            t.add_action(move |starts: &mut Vec<usize>, tok: &Token| {
+                starts.push(tok.start);
+                if tok.start == 0 { ActionResult::Truncate(n) } else { ActionResult::Accept }
+            });
+            let mut starts = vec![];
+            t.apply_bytes(&mut starts, b"ab\xe2\x82cd").unwrap();
+            starts
+        }
+        assert_eq!(rescan_from(2), vec![0, 2]);
+        assert_eq!(rescan_from(3), vec![0, 4]);
+        assert_eq!(rescan_from(4), vec![0, 4]);
+        assert_eq!(rescan_from(5), vec![0, 5]);
+    }
+
+
+    /**
+     * Rules for a quoted string (exclusive condition STR, entered with
+     * Push) and a tag (inclusive condition TAG, entered with Begin).
+     */
+    fn conditions_program(byte_mode: bool) -> Program {
+        let mut tr = RegexTranslator::new();
+        tr.set_byte_mode(byte_mode);
+        let string = tr.add_condition("STR", true);
+        let tag = tr.add_condition("TAG", false);
+        let rules: &[(&str, &[usize])] = &[
+            ("\"", &[]), ("[^\"]+", &[string]), ("\"", &[string]),
+            ("<", &[]), (">", &[tag]), ("[a-z]+", &[]), ("=", &[tag]), (".", &[]),
+        ];
+        for (i, &(rule, conditions)) in rules.iter().enumerate() {
+            let term = parse(rule).unwrap();
+            if conditions.is_empty() {
+                tr.compile(&term, i).unwrap();
+            } else {
+                tr.compile_in(&term, i, conditions).unwrap();
+            }
+        }
+        tr.finish();
+        tr.prog
+    }
+
+    #[test]
+    fn actions_change_the_start_condition() {
+        let (string, tag) = (1, 2);
+        for engine in engines_for(conditions_program) {
+            let mut t = Tokenizer::new(engine);
+            t.add_action(move |_: &mut Seen, _: &Token| ActionResult::Push(string));
+            t.add_action(|_: &mut Seen, _: &Token| ActionResult::Accept);
+            t.add_action(|_: &mut Seen, _: &Token| ActionResult::Pop);
+            t.add_action(move |_: &mut Seen, _: &Token| ActionResult::Begin(tag));
+            t.add_action(|_: &mut Seen, _: &Token| ActionResult::Begin(INITIAL));
+            let mut seen = vec![];
+            let found: Seen = t.tokens(&mut seen, "a=\"x y\"<b c=\"d=e\">=\"")
+                .map(|tok| (tok.rule, tok.text.to_string()))
+                .collect();
+            // Inside a string only the STR rules are active; inside a
+            // tag the TAG rules are too, and '=' goes to rule 6, not 7.
+            // The string in the tag pops back to TAG, not INITIAL.
+            assert_eq!(found, owned(&[
+                (5, "a"), (7, "="), (0, "\""), (1, "x y"), (2, "\""),
+                (3, "<"), (5, "b"), (7, " "), (5, "c"), (6, "="),
+                (0, "\""), (1, "d=e"), (2, "\""), (4, ">"), (7, "="), (0, "\""),
+            ]));
+            assert_eq!(t.condition(), string);
+            // Popping the bottom of the stack leaves it there.
+            assert_eq!(t.pop_condition(), INITIAL);
+            assert_eq!(t.pop_condition(), INITIAL);
+        }
+    }
+
+    #[test]
+    fn unknown_start_condition_is_an_error() {
+        for mut engine in engines_for(conditions_program) {
+            assert_eq!(engine.set_start_condition(3), Err(ConditionError { condition: 3 }));
+            let mut t = Tokenizer::new(engine);
+            t.add_action(|_: &mut Seen, _: &Token| ActionResult::Begin(9));
+            let mut seen = vec![];
+            let mut scan = t.tokens(&mut seen, "a\"b");
+            assert_eq!(scan.next().map(|tok| tok.rule), Some(5));
+            // The token that asks for it is kept; scanning stops after.
+            assert_eq!(scan.next().map(|tok| tok.rule), Some(0));
+            assert!(scan.next().is_none());
+            assert_eq!(scan.error(), Some(TokenizeError::NoCondition(ConditionError { condition: 9 })));
+            let err = t.apply(&mut seen, "\"").unwrap_err();
+            assert_eq!(err.to_string(), "no start condition 9");
+        }
+    }
+
+
+    #[test]
+    fn a_rejected_match_does_not_break_an_unmatched_run() {
+        let mut t = tokenizer(&["a", "b"]);
+        t.set_unmatched_policy(UnmatchedPolicy::Coalesce);
+        t.add_action(|seen: &mut Seen, tok: &Token| {
+            seen.push((tok.rule, tok.text.to_string()));
+            ActionResult::Reject
+        });
+        t.add_action(|seen: &mut Seen, tok: &Token| seen.push((tok.rule, tok.text.to_string())));
+        t.set_unmatched_action(|seen: &mut Seen, tok: &Token| seen.push((tok.rule, tok.text.to_string())));
+        let mut seen = vec![];
+        let found: Seen = t.tokens(&mut seen, "xaxbx")
+            .map(|tok| (tok.rule, tok.text.to_string()))
+            .collect();
+        assert_eq!(found, owned(&[(UNMATCHED, "xax"), (1, "b"), (UNMATCHED, "x")]));
+        // The action of the match that ends a run runs first.
+        assert_eq!(seen, owned(&[(0, "a"), (1, "b"), (UNMATCHED, "xax"), (UNMATCHED, "x")]));
+    }
+
+    #[test]
+    fn condition_changes_follow_the_text_when_a_run_ends() {
+        // The run's Pop is made before the match's Push, though the
+        // match's action runs first. The other way round, the Pop would
+        // undo the Push.
+        let mut t = tokenizer(&["a"]);
+        t.set_unmatched_policy(UnmatchedPolicy::Coalesce);
+        t.add_action(|seen: &mut Seen, tok: &Token| {
+            seen.push((tok.rule, tok.text.to_string()));
+            ActionResult::Push(1)
+        });
+        t.set_unmatched_action(|seen: &mut Seen, tok: &Token| {
+            seen.push((tok.rule, tok.text.to_string()));
+            ActionResult::Pop
+        });
+        let mut seen = vec![];
+        let found: Seen = t.tokens(&mut seen, "xa").map(|tok| (tok.rule, tok.text.to_string())).collect();
+        assert_eq!(found, owned(&[(UNMATCHED, "x"), (0, "a")]));
+        assert_eq!(seen, owned(&[(0, "a"), (UNMATCHED, "x")]));
+        assert_eq!(t.condition(), 1);
+    }
+
+
+    #[test]
+    fn scan_without_actions_agrees_with_tokens() {
+        let rules = ["[a-z]+", r"\s+", "é/!"];
+        let text = "abc 12 dé!é?? x";
+        for &policy in &[UnmatchedPolicy::SkipChar, UnmatchedPolicy::Coalesce] {
+            let mut engine = LazyDfa::new(compile(&rules), vec![]);
+            let want: Seen = Tokens::new(&mut engine, text)
+                .with_unmatched_policy(policy)
+                .map(|tok| (tok.rule, tok.text.to_string()))
+                .collect();
+            let mut t = tokenizer(&rules);
+            t.set_unmatched_policy(policy);
+            assert_eq!(scan(&mut t, text), want);
+        }
+    }
+
+
+    #[test]
+    fn actions_share_a_context_with_plain_functions() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static SPACES: AtomicUsize = AtomicUsize::new(0);
+        fn count_space(text: &str) {
+            SPACES.fetch_add(text.len(), Ordering::SeqCst);
+        }
+
+        // Closures keep their own state, and get the caller's context;
+        // an action returning nothing keeps its token.
+        let mut t = tokenizer(&[r"[a-z]+", r"\d+", r"\s+"]);
+        let mut words = 0;
+        t.add_action(move |seen: &mut Seen, tok: &Token| {
+            words += 1;
+            seen.push((words, tok.text.to_uppercase()));
+        });
+        t.add_action(|seen: &mut Seen, tok: &Token| {
+            seen.push((tok.rule, tok.text.to_string()));
+            ActionResult::Skip
+        });
+        t.add_action(count_space as TokenizerAction);
+
+        let mut seen = vec![];
+        let kept: Vec<_> = t.tokens(&mut seen, "ab 12  cd").map(|tok| tok.rule).collect();
+        assert_eq!(kept, [0, 2, 2, 0]);
+        assert_eq!(seen, owned(&[(1, "AB"), (1, "12"), (2, "CD")]));
+        assert_eq!(SPACES.load(Ordering::SeqCst), 3);
+    }
+}
@@ -1,14 +1,59 @@
-use std::collections::HashMap;
-use reterm::{Term, CharClassData};
-use reprog::{Program, Label, Instruction};
-use reprog::{CharInstData, AnyCharInst, MatchInst, CharClassInst};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use reterm::{Term, CharClassData, CharClassPredicate, Assertion, case_orbit};
+use reprog::{Program, Label, Instruction, TrailInfo};
+use reprog::{CharInstData, AnyCharInst, MatchInst, CharClassInst, AssertInst};
+use reprog::{ByteRangeInst, ByteSwitchInst};
 use reprog::Instruction::*;
 use reterm::TermType::*;
+use utf8::{self, ByteSeq};
+
+/**
+ * Default cap on the number of instructions a single rule may compile to.
+ * Bounded repetitions are expanded by copying their sub-expression, so
+ * without a cap something like 'a{1000}{1000}' would happily generate a
+ * million instructions.
+ */
+pub const DEFAULT_SIZE_LIMIT: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub enum TranslateError {
+    /// The rule would expand to `size` instructions, more than `limit`.
+    TooLarge { rule: usize, size: usize, limit: usize },
+    /// The rule names a start condition the program does not have.
+    UnknownCondition { rule: usize, condition: usize },
+}
+
+impl fmt::Display for TranslateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TranslateError::TooLarge { rule, size, limit } => {
+                write!(f, "rule {} compiles to {} instructions (limit is {})",
+                       rule, size, limit)
+            }
+            TranslateError::UnknownCondition { rule, condition } => {
+                write!(f, "rule {} names unknown start condition {}", rule, condition)
+            }
+        }
+    }
+}
+
+impl Error for TranslateError {}
+
 
 pub struct RegexTranslator {
     pub prog: Program,
     next_label: usize,
     label_map: HashMap<Label, Label>,
+    size_limit: usize,
+    byte_classes: Vec<(CharClassData, Vec<ByteSeq>)>,  // byte mode: classes seen so far
+}
+
+impl Default for RegexTranslator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RegexTranslator {
@@ -17,9 +62,28 @@ impl RegexTranslator {
             prog:        Program::new(),
             next_label:  0,
             label_map:   HashMap::new(),
+            size_limit:  DEFAULT_SIZE_LIMIT,
+            byte_classes: vec![],
         }
     }
 
+    /**
+     * Set the maximum number of instructions any one rule may compile to.
+     */
+    pub fn set_size_limit(&mut self, limit: usize) {
+        self.size_limit = limit;
+    }
+
+    /**
+     * Compile characters, classes and '.' to ranges of bytes of their
+     * UTF-8 encodings (see utf8.rs), so the program can be run a byte
+     * at a time. This has to be decided before compiling any rules.
+     */
+    pub fn set_byte_mode(&mut self, byte_mode: bool) {
+        assert!(self.prog.is_empty(), "set_byte_mode() after compiling rules");
+        self.prog.set_byte_mode(byte_mode);
+    }
+
     pub fn get_program(&self) -> &Program {
         &self.prog
     }
@@ -30,20 +94,97 @@ impl RegexTranslator {
         nxt
     }
 
-    pub fn compile(&mut self, regex: &Term, rule_nbr: usize) {
+    /**
+     * Declare a start condition (flex's %s, or %x if exclusive), for
+     * use with compile_in().
+     */
+    pub fn add_condition(&mut self, name: &str, exclusive: bool) -> usize {
+        self.prog.add_condition(name, exclusive)
+    }
+
+    /**
+     * Append the code for one rule to the program. Nothing is added if
+     * the rule would exceed the size limit.
+     */
+    pub fn compile(&mut self, regex: &Term, rule_nbr: usize) -> Result<(), TranslateError> {
+        self.compile_rule(regex, rule_nbr, None)
+    }
+
+    /**
+     * Like compile(), but the rule is only active in the given start
+     * conditions, as with flex's <SC1,SC2>regex.
+     */
+    pub fn compile_in(
+        &mut self, 
+        regex: &Term, 
+        rule_nbr: usize, 
+        conditions: &[usize],
+    ) -> Result<(), TranslateError> {
+        self.compile_rule(regex, rule_nbr, Some(conditions))
+    }
+
+    fn compile_rule(
+        &mut self, 
+        regex: &Term, 
+        rule_nbr: usize, 
+        conditions: Option<&[usize]>,
+    ) -> Result<(), TranslateError> {
+        let size = inst_count(regex, &mut |leaf| self.leaf_size(leaf)).saturating_add(1);   // + Match
+        if size > self.size_limit {
+            return Err(TranslateError::TooLarge {
+                rule: rule_nbr,
+                size,
+                limit: self.size_limit,
+            });
+        }
         let start = self.prog.len();
-        self.prog.add_start(start);
+        match conditions {
+            None => self.prog.add_start(start),
+            Some(conditions) => {
+                let count = self.prog.conditions.len();
+                if let Some(&bad) = conditions.iter().find(|&&sc| sc >= count) {
+                    return Err(TranslateError::UnknownCondition {
+                        rule: rule_nbr,
+                        condition: bad,
+                    });
+                }
+                self.prog.add_start_in(start, conditions);
+            }
+        }
         self.translate_root(regex, rule_nbr);
+        if let TrailingContext = regex.op {
+            let info = TrailInfo {
+                head_len: fixed_len(&regex.subs[0]),
+                tail_len: fixed_len(&regex.subs[1]),
+            };
+            self.prog.add_trailing(rule_nbr, start, info);
+        }
+        Ok(())
     }
 
     pub fn finish(&mut self) {
         self.prog.ground_labels(&self.label_map);
     }
 
+    /*
+        translate_root(r/s):
+            L1: translate(r, L1, LM)
+            LM: mark LS
+            LS: translate(s, LS, L2)
+            L2: match
+    */
     fn translate_root(&mut self, regex: &Term, rule_nbr: usize) {
         let l1 = self.gen_label();
         let l2 = self.gen_label();
-        self.translate(regex, l1, l2);
+        if let TrailingContext = regex.op {
+            let lm = self.gen_label();
+            let ls = self.gen_label();
+            self.translate(&regex.subs[0], l1, lm);
+            self.emit(Mark(ls), lm);
+            self.translate(&regex.subs[1], ls, l2);
+        } else {
+            self.translate(regex, l1, l2);
+        }
         self.emit(Match(MatchInst {rule_id: rule_nbr, /*goto: l2*/}), l2);
     }
 
@@ -54,9 +195,14 @@ impl RegexTranslator {
             Iteration => self.trans_iter(regex, l0, l),
             Optional => self.trans_opt(regex, l0, l),
             PositiveIteration => self.trans_pos(regex, l0, l),
+            Repetition { min, max } => self.trans_rep(regex, min, max, l0, l),
             Atom(c, nocase) => self.trans_char(c, nocase, l0, l),
             CharClassTerm(ref ccd, nocase) => self.trans_chcls(ccd, nocase, l0, l),
-            AnyCharTerm => self.trans_any_char(l0, l),
+            AnyCharTerm(dot_nl) => self.trans_any_char(dot_nl, l0, l),
+            AssertionTerm(kind) => self.trans_assert(kind, l0, l),
+            InvalidTerm => self.trans_invalid(l0, l),
+            Empty => self.trans_empty(l0, l),
+            TrailingContext => unreachable!("trailing context below the root"),
         }
     }
 
@@ -66,11 +212,6 @@ impl RegexTranslator {
         self.label_map.insert(at_line, n);
     }
 
-    /*
-        translate(a, L0) -->
-            char a goto L0
-    */
-
     /*
         translate(e1|e2, L0, L):
             L0: split L1, L2
@@ -129,29 +270,407 @@ impl RegexTranslator {
         self.emit(Split(l0, l), l1);
     }
 
+    /*
+        translate(e{n,m}, L0, L):
+            L0: translate(e, L0, L1)        -- n mandatory copies
+            L1: translate(e, L1, L2)
+            ...
+            Ln: split A1, L                 -- m - n optional copies
+            A1: translate(e, A1, Ln+1)
+            Ln+1: split A2, L
+            ...
+        For e{n,}, the optional copies are replaced by a single e*.
+        For e{0} (or e{0,0}) there is nothing to match, so we translate
+        it like the empty term.
+    */
+    fn trans_rep(&mut self, regex: &Term, 
+                 min: usize, max: Option<usize>, 
+                 l0: Label, l: Label) {
+        let sub = &regex.subs[0];
+        if max == Some(0) {
+            self.trans_empty(l0, l);
+            return;
+        }
+        let mut from = l0;
+        for i in 0..min {
+            let to = if i + 1 == min && max == Some(min) { l } else { self.gen_label() };
+            self.translate(sub, from, to);
+            from = to;
+        }
+        match max {
+            Some(m) => {
+                for i in min..m {
+                    let body = self.gen_label();
+                    let to = if i + 1 == m { l } else { self.gen_label() };
+                    self.emit(Split(body, l), from);
+                    self.translate(sub, body, to);
+                    from = to;
+                }
+            }
+            None => {
+                let body = self.gen_label();
+                self.emit(Split(body, l), from);
+                self.translate(sub, body, from);
+            }
+        }
+    }
+
+    /*
+        translate(<empty>, L0, L):
+            L0: split L, L
+        which is the closest thing we have to an epsilon transition.
+    */
+    fn trans_empty(&mut self, l0: Label, l: Label) {
+        self.emit(Split(l, l), l0);
+    }
+
+    /*
+        translate(a, L0, L):
+            L0: char a goto L
+        Case-insensitivity is resolved here rather than in the interpreter:
+        if a has other case forms, we emit a class of all of them instead.
+    */
     fn trans_char(&mut self, c: char, nocase: bool, l0: Label, l: Label) {
-        self.emit(Char(CharInstData {ch: c, nocase: nocase, goto: l} ), l0);
+        let orbit = case_orbit(c);
+        if nocase && orbit.len() > 1 {
+            let preds = orbit.into_iter().map(CharClassPredicate::Individual).collect();
+            self.trans_chcls(&CharClassData::new(true, preds), false, l0, l);
+        } else if self.prog.byte_mode() {
+            self.trans_bytes(&utf8::sequences(&[(c, c)]), l0, l);
+        } else {
+            self.emit(Char(CharInstData {ch: c, goto: l} ), l0);
+        }
     }
 
-    fn trans_any_char(&mut self, l0: Label, l: Label) {
-        self.emit(AnyChar(AnyCharInst {goto: l}), l0);
+    fn trans_any_char(&mut self, dot_nl: bool, l0: Label, l: Label) {
+        if self.prog.byte_mode() {
+            self.trans_bytes(&utf8::sequences(&any_char_ranges(dot_nl)), l0, l);
+        } else {
+            self.emit(AnyChar(AnyCharInst {dot_nl, goto: l}), l0);
+        }
+    }
+
+    /*
+        translate(^, L0, L):
+            L0: assert ^ goto L
+    */
+    fn trans_assert(&mut self, kind: Assertion, l0: Label, l: Label) {
+        self.emit(Assert(AssertInst {kind, goto: l}), l0);
+    }
+
+    /*
+        translate(\I, L0, L):
+            L0: invalid goto L
+        The same in byte mode: an invalid sequence is not made of bytes
+        any instruction could test.
+    */
+    fn trans_invalid(&mut self, l0: Label, l: Label) {
+        self.emit(Invalid(l), l0);
     }
 
     /*
         translate([es], L0, L:
             L0: charclass es goto L
+        A case-insensitive class is closed under case folding first.
     */
     fn trans_chcls(&mut self, 
                    clsdata: &CharClassData, nocase: bool, 
                    l0: Label, l: Label) {
+        let data = if nocase { clsdata.case_fold() } else { clsdata.clone() };
+        if self.prog.byte_mode() {
+            let i = self.byte_class(data);
+            let seqs = self.byte_classes[i].1.clone();
+            self.trans_bytes(&seqs, l0, l);
+            return;
+        }
         self.emit(CharClass(CharClassInst {
-            data: clsdata.clone(),
-            nocase: nocase,
+            data,
             goto: l,
         }), l0);
     }
 
+    /*
+        translate(byte sequences, L0, L), in byte mode:
+            L0: byte switch [lo1-hi1] goto B1, [lo2-hi2] goto B2, ...
+            B1: translate(the rest of the sequences starting lo1-hi1, B1, L)
+            B2: ...
+        with one arm per leading range, so the code is a trie. Sequences
+        of one byte go straight to L, and a single arm is just a byte
+        range. No sequences at all (an empty class) can not match
+        anything, which is what byte FF does, since it is never part of
+        UTF-8. Different arms often have the same rest, most of all the
+        continuation bytes [80-BF]{n}, so each rest is only translated
+        once and shared, as in RE2 and the regex crate.
+    */
+    fn trans_bytes(&mut self, seqs: &[ByteSeq], l0: Label, l: Label) {
+        let mut rests = HashMap::new();
+        self.trans_byte_trie(seqs, l0, l, &mut rests);
+    }
+
+    fn trans_byte_trie(
+        &mut self,
+        seqs: &[ByteSeq],
+        l0: Label,
+        l: Label,
+        rests: &mut HashMap<Vec<ByteSeq>, Label>,
+    ) {
+        if seqs.is_empty() {
+            self.emit(ByteRange(ByteRangeInst { lo: 0xFF, hi: 0xFF, goto: l }), l0);
+            return;
+        }
+        // The rests go after this instruction, which has to come first,
+        // since it may be the start of a rule. Fill it in afterwards.
+        self.emit(ByteRange(ByteRangeInst { lo: 0xFF, hi: 0xFF, goto: l }), l0);
+        let pc = self.prog.len() - 1;
+        let mut arms: Vec<ByteRangeInst> = vec![];
+        for group in group_by_first(seqs) {
+            let (lo, hi) = group[0][0];
+            let goto = if group[0].len() == 1 {
+                l
+            } else {
+                let rest: Vec<ByteSeq> = group.iter().map(|seq| seq[1..].to_vec()).collect();
+                match rests.get(&rest) {
+                    Some(&rest_label) => rest_label,
+                    None => {
+                        let rest_label = self.gen_label();
+                        self.trans_byte_trie(&rest, rest_label, l, rests);
+                        rests.insert(rest, rest_label);
+                        rest_label
+                    }
+                }
+            };
+            // Neighbours going to the same place can be one arm.
+            match arms.last_mut() {
+                Some(last) if last.goto == goto && last.hi as usize + 1 == lo as usize => {
+                    last.hi = hi;
+                }
+                _ => arms.push(ByteRangeInst { lo, hi, goto }),
+            }
+        }
+        self.prog[pc] = if arms.len() == 1 {
+            ByteRange(arms[0])
+        } else {
+            ByteSwitch(ByteSwitchInst { arms })
+        };
+    }
+
+    /**
+     * Index of a class in byte_classes, working out its byte sequences
+     * the first time we see it.
+     */
+    fn byte_class(&mut self, data: CharClassData) -> usize {
+        match self.byte_classes.iter().position(|(d, _)| *d == data) {
+            Some(i) => i,
+            None => {
+                let seqs = utf8::sequences(&data.char_ranges());
+                self.byte_classes.push((data, seqs));
+                self.byte_classes.len() - 1
+            }
+        }
+    }
+
+    /**
+     * The number of instructions translate() will emit for a character,
+     * class or '.'.
+     */
+    fn leaf_size(&mut self, leaf: &Term) -> usize {
+        if !self.prog.byte_mode() {
+            return 1;
+        }
+        match leaf.op {
+            Atom(c, nocase) if !nocase || case_orbit(c).len() == 1 => {
+                c.len_utf8()
+            }
+            Atom(c, _) => {
+                let preds = case_orbit(c).into_iter().map(CharClassPredicate::Individual).collect();
+                let i = self.byte_class(CharClassData::new(true, preds));
+                byte_code_size(&self.byte_classes[i].1)
+            }
+            CharClassTerm(ref ccd, nocase) => {
+                let data = if nocase { ccd.case_fold() } else { ccd.clone() };
+                let i = self.byte_class(data);
+                byte_code_size(&self.byte_classes[i].1)
+            }
+            AnyCharTerm(dot_nl) => byte_code_size(&utf8::sequences(&any_char_ranges(dot_nl))),
+            _ => 1,
+        }
+    }
+
     pub fn print_prog(&self) {
         self.prog.print();
     }
 }
+
+
+/**
+ * The characters '.' matches.
+ */
+fn any_char_ranges(dot_nl: bool) -> Vec<(char, char)> {
+    if dot_nl {
+        vec![('\0', ::std::char::MAX)]
+    } else {
+        vec![('\0', '\u{9}'), ('\u{B}', ::std::char::MAX)]
+    }
+}
+
+/**
+ * Split sorted byte sequences into runs that start with the same range.
+ */
+fn group_by_first(seqs: &[ByteSeq]) -> Vec<&[ByteSeq]> {
+    let mut groups = vec![];
+    let mut start = 0;
+    for i in 1..=seqs.len() {
+        if i == seqs.len() || seqs[i][0] != seqs[start][0] {
+            groups.push(&seqs[start..i]);
+            start = i;
+        }
+    }
+    groups
+}
+
+/**
+ * The number of instructions trans_bytes() will emit for sequences.
+ */
+fn byte_code_size(seqs: &[ByteSeq]) -> usize {
+    byte_trie_size(seqs, &mut HashSet::new())
+}
+
+fn byte_trie_size(seqs: &[ByteSeq], rests: &mut HashSet<Vec<ByteSeq>>) -> usize {
+    let mut size = 1;
+    for group in group_by_first(seqs) {
+        if group[0].len() > 1 {
+            let rest: Vec<ByteSeq> = group.iter().map(|seq| seq[1..].to_vec()).collect();
+            if !rests.contains(&rest) {
+                size += byte_trie_size(&rest, rests);
+                rests.insert(rest);
+            }
+        }
+    }
+    size
+}
+
+/**
+ * The number of instructions translate() will emit for a term, given
+ * the number for each character, class or '.' (just 1, except in byte
+ * mode). Saturates rather than overflowing, since the point is to
+ * catch terms that are absurdly large.
+ */
+fn inst_count(regex: &Term, leaf: &mut dyn FnMut(&Term) -> usize) -> usize {
+    let sub = |leaf: &mut dyn FnMut(&Term) -> usize| inst_count(&regex.subs[0], leaf);
+    match regex.op {
+        Alternation => {
+            1usize.saturating_add(inst_count(&regex.subs[0], leaf))
+                  .saturating_add(inst_count(&regex.subs[1], leaf))
+        }
+        Concatenation => {
+            inst_count(&regex.subs[0], leaf).saturating_add(inst_count(&regex.subs[1], leaf))
+        }
+        Iteration | PositiveIteration | Optional => sub(leaf).saturating_add(1),
+        Repetition { min, max } => {
+            let n = sub(leaf);
+            match max {
+                Some(0) => 1,
+                Some(m) => {
+                    n.saturating_mul(min)
+                     .saturating_add(n.saturating_add(1).saturating_mul(m - min))
+                }
+                None => n.saturating_mul(min).saturating_add(n).saturating_add(1),
+            }
+        }
+        Atom(..) | CharClassTerm(..) | AnyCharTerm(..) => leaf(regex),
+        AssertionTerm(..) | InvalidTerm | Empty => 1,
+        TrailingContext => {
+            inst_count(&regex.subs[0], leaf).saturating_add(1)       // + Mark
+                                            .saturating_add(inst_count(&regex.subs[1], leaf))
+        }
+    }
+}
+
+/**
+ * The number of characters every match of a term has, if they all
+ * have the same number.
+ */
+fn fixed_len(regex: &Term) -> Option<usize> {
+    match regex.op {
+        Alternation => {
+            let n = fixed_len(&regex.subs[0])?;
+            if fixed_len(&regex.subs[1]) == Some(n) { Some(n) } else { None }
+        }
+        Concatenation | TrailingContext => {
+            fixed_len(&regex.subs[0])?.checked_add(fixed_len(&regex.subs[1])?)
+        }
+        Iteration | PositiveIteration | Optional => {
+            match fixed_len(&regex.subs[0])? {
+                0 => Some(0),
+                _ => None,
+            }
+        }
+        Repetition { min, max } => {
+            let n = fixed_len(&regex.subs[0])?;
+            match max {
+                Some(m) if m == min || n == 0 => n.checked_mul(m),
+                None if n == 0 => Some(0),
+                _ => None,
+            }
+        }
+        Atom(..) | CharClassTerm(..) | AnyCharTerm(..) => Some(1),
+        AssertionTerm(..) | Empty => Some(0),
+        // One sequence, but from one to three characters of the text.
+        InvalidTerm => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reparse::parse;
+    use testutil::matches_whole;
+
+    #[test]
+    fn bounded_repetition() {
+        let cases = [
+            ("a{3}", "aaa", true),
+            ("a{3}", "aa", false),
+            ("a{3}", "aaaa", false),
+            ("a{2,}", "a", false),
+            ("a{2,}", "aaaaaa", true),
+            ("a{2,4}", "aa", true),
+            ("a{2,4}", "aaaa", true),
+            ("a{2,4}", "aaaaa", false),
+            ("x{0}y", "y", true),
+            ("x{0,1}y", "xy", true),
+            ("(ab|c){1,2}d", "abcd", true),
+            ("(ab|c){1,2}d", "abcabd", false),
+            ("a{2}{3}", "aaaaaa", true),
+            ("a{2}{3}", "aaaa", false),
+        ];
+        for &(rule, text, want) in cases.iter() {
+            assert_eq!(matches_whole(rule, text), want, "{:?} on {:?}", rule, text);
+        }
+    }
+
+    #[test]
+    fn braces_that_are_not_quantifiers_are_literal() {
+        assert!(matches_whole("a{,3}", "a{,3}"));
+        assert!(matches_whole("a{x}", "a{x}"));
+        assert!(matches_whole("a{", "a{"));
+        assert!(matches_whole("{2}", "{2}"));
+    }
+
+    #[test]
+    fn repetition_counts_towards_size_limit() {
+        let mut tr = RegexTranslator::new();
+        tr.set_size_limit(10_000);
+        let err = tr.compile(&parse("a{100}{1000}").unwrap(), 0).unwrap_err();
+        assert!(matches!(err, TranslateError::TooLarge { rule: 0, limit: 10_000, .. }));
+        assert!(tr.compile(&parse("a{100}{10}").unwrap(), 0).is_ok());
+    }
+
+    #[test]
+    fn word_repetition_fits_in_both_modes() {
+        assert!(matches_whole(r"\w{8}", "naïveté_"));
+        assert!(matches_whole(r"\w{8}", "Ελληνικά"));
+        assert!(!matches_whole(r"\w{8}", "naïve té"));
+    }
+}
@@ -0,0 +1,126 @@
+/*
+ * Fixtures shared by the unit tests: sample rules and text, compiling
+ * rules, building each kind of engine for them, and listing what the
+ * engines find.
+ */
+
+use reparse::parse;
+use retrans::RegexTranslator;
+use reprog::Program;
+use reinterp::{MatchEngine, ThompsonInterpreter, Tokens};
+use relazy::LazyDfa;
+use redfa::{DfaBuilder, DfaError, DfaInterpreter};
+
+
+/**
+ * Rules that between them use most of what the engines can do, and a
+ * text that gives each of them something to match.
+ */
+pub const RULES: &[&str] = &[
+    r"\bif\b",
+    r"[a-z]+/=",
+    r"[A-Za-z_]\w*",
+    r"\d+(\.\d+)?",
+    r"\s+",
+    r"^#[^\n]*",
+    r#""([^"\\]|\\.)*""#,
+    r"[-+*/=<>!]=?",
+    r"\p{Greek}+",
+    r".",
+];
+
+pub const TEXT: &str = "# comment\nif x1 >= 3.14 { y= \"a\\\"b\" } else iffy\n\
+                        αβγ == ÷ émigré_2; #not a comment\n";
+
+
+/**
+ * Compile rules, which are numbered in order.
+ */
+pub fn compile(rules: &[&str]) -> Program {
+    compile_mode(rules, false)
+}
+
+/**
+ * Compile rules to a program over characters, or over bytes.
+ */
+pub fn compile_mode(rules: &[&str], byte_mode: bool) -> Program {
+    let mut tr = RegexTranslator::new();
+    tr.set_byte_mode(byte_mode);
+    for (i, rule) in rules.iter().enumerate() {
+        tr.compile(&parse(rule).unwrap(), i).unwrap();
+    }
+    tr.finish();
+    tr.prog
+}
+
+/**
+ * Every engine for rules, in both modes: the NFA, the lazy DFA, and
+ * the DFA, unless the rules are beyond it.
+ */
+pub fn engines(rules: &[&str]) -> Vec<Box<dyn MatchEngine>> {
+    engines_for(|byte_mode| compile_mode(rules, byte_mode))
+}
+
+/**
+ * Every engine for the program compile(byte_mode) gives, as engines()
+ * does, for programs that need more than a list of rules.
+ */
+pub fn engines_for<F: Fn(bool) -> Program>(compile: F) -> Vec<Box<dyn MatchEngine>> {
+    let mut engines: Vec<Box<dyn MatchEngine>> = vec![];
+    for &byte_mode in &[false, true] {
+        let prog = compile(byte_mode);
+        match DfaBuilder::new().build(&prog) {
+            Ok(dfa) => engines.push(Box::new(DfaInterpreter::new(dfa, vec![]))),
+            Err(DfaError::WordBoundaryInBytes) |
+            Err(DfaError::VariableTrailingContext { .. }) => {}
+            Err(e) => panic!("{}", e),
+        }
+        engines.push(Box::new(ThompsonInterpreter::new(prog.clone(), vec![])));
+        engines.push(Box::new(LazyDfa::new(prog, vec![])));
+    }
+    engines
+}
+
+/**
+ * The lengths of rule's matches at &text[start..], counting any
+ * trailing context. Every engine is asked, and they have to agree.
+ */
+pub fn match_lengths(rule: &str, text: &str, start: usize) -> Vec<usize> {
+    let answers: Vec<Vec<usize>> = engines(&[rule]).iter_mut().map(|engine| {
+        engine.all_matches_at(text, start);
+        let mut lens: Vec<usize> = engine.matches().iter().map(|m| m.total_len()).collect();
+        lens.sort();
+        lens.dedup();
+        lens
+    }).collect();
+    assert!(answers.iter().all(|a| *a == answers[0]),
+            "engines disagree on {:?} for {:?} at {}: {:?}", rule, text, start, answers);
+    answers[0].clone()
+}
+
+/**
+ * Whether rule matches all of text, by match_lengths().
+ */
+pub fn matches_whole(rule: &str, text: &str) -> bool {
+    match_lengths(rule, text, 0).contains(&text.len())
+}
+
+/**
+ * The (rule, length) of every match at every position of text.
+ */
+pub fn all_matches<E: MatchEngine + ?Sized>(engine: &mut E, text: &str) -> Vec<Vec<(usize, usize)>> {
+    text.char_indices().map(|(i, _)| {
+        engine.all_matches_at(text, i);
+        let mut found: Vec<_> = engine.matches().iter().map(|m| (m.rule, m.len)).collect();
+        found.sort();
+        found.dedup();
+        found
+    }).collect()
+}
+
+/**
+ * The (rule, text) of each token of text.
+ */
+pub fn tokens<'t, E: MatchEngine + ?Sized>(engine: &mut E, text: &'t str) -> Vec<(usize, &'t str)> {
+    Tokens::new(engine, text).map(|tok| (tok.rule, tok.text)).collect()
+}
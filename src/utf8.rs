@@ -0,0 +1,87 @@
+/*
+ * Character ranges as UTF-8 byte sequences.
+ *
+ * To match a set of characters a byte at a time, we need the set of
+ * their UTF-8 encodings as a small automaton. Following RE2 and the
+ * Rust regex crate (regex-syntax's Utf8Sequences), we split each range
+ * of characters into pieces whose encodings all have the same length,
+ * and whose bytes vary independently, e.g.
+ *
+ *     U+0080..U+07FF  =>  [C2-DF][80-BF]
+ *
+ * so that each piece is just a sequence of byte ranges.
+ */
+
+pub type ByteSeq = Vec<(u8, u8)>;
+
+/**
+ * The byte sequences for some characters, as sorted, disjoint ranges
+ * (see CharClassData::char_ranges()). Every encoding of a character in
+ * the ranges matches exactly one of the sequences, and nothing else
+ * matches any of them. The sequences come out in byte order.
+ */
+pub fn sequences(ranges: &[(char, char)]) -> Vec<ByteSeq> {
+    let mut seqs = vec![];
+    for &(lo, hi) in ranges {
+        let mut stack = vec![(lo as u32, hi as u32)];
+        while let Some((lo, hi)) = stack.pop() {
+            split(lo, hi, &mut stack, &mut seqs);
+        }
+    }
+    seqs
+}
+
+/**
+ * Either push lo..=hi as a single sequence, or push smaller pieces of
+ * it back on the stack, in reverse order so that they come off in
+ * order.
+ */
+fn split(lo: u32, hi: u32, stack: &mut Vec<(u32, u32)>, seqs: &mut Vec<ByteSeq>) {
+    // Surrogates have no encoding.
+    if lo < 0xE000 && hi > 0xD7FF {
+        if hi >= 0xE000 {
+            stack.push((0xE000, hi));
+        }
+        if lo <= 0xD7FF {
+            stack.push((lo, 0xD7FF));
+        }
+        return;
+    }
+    // Encodings of different lengths.
+    for &max in &[0x7F, 0x7FF, 0xFFFF] {
+        if lo <= max && hi > max {
+            stack.push((max + 1, hi));
+            stack.push((lo, max));
+            return;
+        }
+    }
+    if hi <= 0x7F {
+        seqs.push(vec![(lo as u8, hi as u8)]);
+        return;
+    }
+    // Continuation bytes that do not cover all of 80-BF, where
+    // a leading byte does not stay the same.
+    for i in 1..4 {
+        let m: u32 = (1 << (6 * i)) - 1;
+        if lo & !m != hi & !m {
+            if lo & m != 0 {
+                stack.push(((lo | m) + 1, hi));
+                stack.push((lo, lo | m));
+                return;
+            }
+            if hi & m != m {
+                stack.push((hi & !m, hi));
+                stack.push((lo, (hi & !m) - 1));
+                return;
+            }
+        }
+    }
+    let (mut a, mut b) = ([0; 4], [0; 4]);
+    let lo_bytes = encode(lo, &mut a);
+    let hi_bytes = encode(hi, &mut b);
+    seqs.push(lo_bytes.iter().cloned().zip(hi_bytes.iter().cloned()).collect());
+}
+
+fn encode(ch: u32, buf: &mut [u8; 4]) -> &[u8] {
+    ::std::char::from_u32(ch).unwrap().encode_utf8(buf).as_bytes()
+}
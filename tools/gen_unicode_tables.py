@@ -0,0 +1,224 @@
+#!/usr/bin/env python3
+#
+# gen_unicode_tables.py
+#
+# Writes src/unicode_tables.rs: the general category and script tables
+# behind \p{...} classes, and the simple case folding orbits used for
+# case-insensitive matching. The data comes from these files of the
+# Unicode character database:
+#
+#     UnicodeData.txt  PropertyValueAliases.txt  Scripts.txt  CaseFolding.txt
+#
+# all from https://www.unicode.org/Public/<version>/ucd/ for the version
+# in UNICODE_VERSION below. The script checks the version in the files'
+# headers, so moving to a new version of Unicode is a matter of
+# downloading its files and changing UNICODE_VERSION.
+#
+# Usage: python3 tools/gen_unicode_tables.py UCD_DIR > src/unicode_tables.rs
+
+import os
+import re
+import sys
+
+UNICODE_VERSION = (17, 0, 0)
+
+MAX_CHAR = 0x10FFFF
+
+
+def normalize(name):
+    return "".join(c for c in name.lower() if c not in "_- ")
+
+
+# The data lines of a UCD file, as (fields, comment). The header's
+# first line names the file and its version, e.g. "# Scripts-17.0.0.txt",
+# which has to be UNICODE_VERSION; UnicodeData.txt has no header.
+def read_ucd(ucd_dir, name, check_version=True):
+    with open(os.path.join(ucd_dir, name), encoding="utf-8") as f:
+        lines = f.read().splitlines()
+    if check_version:
+        m = re.match(r"#\s*%s-(\d+)\.(\d+)\.(\d+)\.txt" % re.escape(name[:-4]),
+                     lines[0] if lines else "")
+        if not m:
+            sys.exit("%s: no version in its header" % name)
+        version = tuple(int(v) for v in m.groups())
+        if version != UNICODE_VERSION:
+            sys.exit("%s is for Unicode %d.%d.%d, not %d.%d.%d"
+                     % ((name,) + version + UNICODE_VERSION))
+    rows = []
+    for line in lines:
+        data, _, comment = line.partition("#")
+        if data.strip():
+            rows.append(([field.strip() for field in data.split(";")], comment))
+    return rows
+
+
+# "0041" or "0041..005A" as (lo, hi).
+def code_range(field):
+    lo, _, hi = field.partition("..")
+    return int(lo, 16), int(hi or lo, 16)
+
+
+# The values of property `prop` (e.g. "gc"), as (short name, long name,
+# other aliases) in file order. Grouped general categories (L, LC, ...)
+# list their members in a comment, e.g. "gc ; L ; Letter # Ll | Lm | ...",
+# which is returned as a map from group to members.
+def property_values(ucd_dir, prop):
+    values = []
+    groups = {}
+    for fields, comment in read_ucd(ucd_dir, "PropertyValueAliases.txt"):
+        if fields[0] != prop:
+            continue
+        values.append((fields[1], fields[2], fields[3:]))
+        if "|" in comment:
+            groups[fields[1]] = [m.strip() for m in comment.split("|")]
+    return values, groups
+
+
+# The general category of every code point, from UnicodeData.txt. Big
+# blocks of similar characters (CJK ideographs, Hangul syllables) are
+# given as a pair of lines, "<..., First>" and "<..., Last>". Anything
+# not listed is unassigned, Cn.
+def general_categories(ucd_dir):
+    gc = ["Cn"] * (MAX_CHAR + 1)
+    first = None
+    for fields, _ in read_ucd(ucd_dir, "UnicodeData.txt", check_version=False):
+        cp, name, cat = int(fields[0], 16), fields[1], fields[2]
+        if name.endswith(", First>"):
+            first = cp
+            continue
+        lo = first if name.endswith(", Last>") else cp
+        first = None
+        for c in range(lo, cp + 1):
+            gc[c] = cat
+    return gc
+
+
+# The script of every code point, from Scripts.txt, by long name.
+# Anything not listed is Unknown.
+def scripts(ucd_dir):
+    sc = ["Unknown"] * (MAX_CHAR + 1)
+    for fields, _ in read_ucd(ucd_dir, "Scripts.txt"):
+        lo, hi = code_range(fields[0])
+        for c in range(lo, hi + 1):
+            sc[c] = fields[1]
+    return sc
+
+
+# The [lo, hi] ranges of the code points with each value, minus the
+# surrogates, which can never be a Rust char.
+def ranges_by_value(per_cp):
+    out = {}
+    for cp, value in enumerate(per_cp):
+        rs = out.setdefault(value, [])
+        if 0xD800 <= cp <= 0xDFFF:
+            continue
+        if rs and rs[-1][1] == cp - 1:
+            rs[-1][1] = cp
+        else:
+            rs.append([cp, cp])
+    return out
+
+
+# The union of some values' ranges, sorted, with adjacent ones merged.
+def merge(range_lists):
+    out = []
+    for lo, hi in sorted(r for rs in range_lists for r in rs):
+        if out and out[-1][1] == lo - 1:
+            out[-1][1] = hi
+        else:
+            out.append([lo, hi])
+    return [tuple(r) for r in out]
+
+
+# Write a table from each value's canonical name to its ranges, and an
+# alias table from each of its normalized names to its index. `members`
+# maps each value (by canonical name) to the values in the per-code-point
+# list that make it up. Values that no code point has are left out (like
+# the script Katakana_Or_Hiragana), but not Cs, which only has surrogates.
+def emit_table(const, per_cp, members, aliases):
+    by_value = ranges_by_value(per_cp)
+    table = []
+    for name in sorted(members):
+        if any(m in by_value for m in members[name]):
+            table.append((name, merge(by_value.get(m, []) for m in members[name])))
+    print("pub const %s: &[(&str, &[(char, char)])] = &[" % const)
+    for name, rs in table:
+        print("    (\"%s\", &[" % name)
+        for r in rs:
+            print("        ('\\u{%X}', '\\u{%X}')," % r)
+        print("    ]),")
+    print("];\n")
+    alias = {}
+    for i, (name, _) in enumerate(table):
+        for a in aliases[name]:
+            alias[normalize(a)] = i
+    print("pub const %s_ALIASES: &[(&str, usize)] = &[" % const)
+    for a in sorted(alias):
+        print("    (\"%s\", %d)," % (a, alias[a]))
+    print("];\n")
+
+
+# General categories go by their short names (Lu), and the per-code-point
+# values from UnicodeData.txt are short names too.
+def emit_general_categories(ucd_dir):
+    values, groups = property_values(ucd_dir, "gc")
+    members = {}
+    aliases = {}
+    for short, long, others in values:
+        members[short] = groups.get(short, [short])
+        aliases[short] = [short, long] + others
+    emit_table("GENERAL_CATEGORY", general_categories(ucd_dir), members, aliases)
+
+
+# Scripts go by their long names (Greek), as in Scripts.txt.
+def emit_scripts(ucd_dir):
+    values, _ = property_values(ucd_dir, "sc")
+    members = {}
+    aliases = {}
+    for short, long, others in values:
+        members[long] = [long]
+        aliases[long] = [short, long] + others
+    emit_table("SCRIPT", scripts(ucd_dir), members, aliases)
+
+
+# Characters that are equivalent under simple case folding (the C and S
+# lines of CaseFolding.txt) form an orbit, e.g. k, K and the Kelvin sign.
+# Each character in a non-trivial orbit maps to the next one up, and the
+# largest wraps around to the smallest, so following the links from any
+# member visits them all.
+def emit_case_orbits(ucd_dir):
+    orbit = {}
+    for fields, _ in read_ucd(ucd_dir, "CaseFolding.txt"):
+        if fields[1] not in ("C", "S"):
+            continue
+        cp, target = int(fields[0], 16), int(fields[2], 16)
+        orbit.setdefault(target, {target}).add(cp)
+    nxt = {}
+    for members in orbit.values():
+        m = sorted(members)
+        for i, cp in enumerate(m):
+            nxt[cp] = m[(i + 1) % len(m)]
+    print("pub const CASE_FOLDING_ORBITS: &[(char, char)] = &[")
+    for cp in sorted(nxt):
+        print("    ('\\u{%X}', '\\u{%X}')," % (cp, nxt[cp]))
+    print("];")
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: %s UCD_DIR > src/unicode_tables.rs" % sys.argv[0])
+    ucd_dir = sys.argv[1]
+    print("// DO NOT EDIT: generated by tools/gen_unicode_tables.py")
+    print("// from the Unicode %d.%d.%d character database." % UNICODE_VERSION)
+    print("//")
+    print("// Each table maps a canonical name to its sorted, non-overlapping")
+    print("// code point ranges. The alias tables map normalized names (lower")
+    print("// case, no '_', '-' or ' ') to an index into the main table.\n")
+    print("pub const UNICODE_VERSION: (u32, u32, u32) = (%d, %d, %d);\n"
+          % UNICODE_VERSION)
+    emit_general_categories(ucd_dir)
+    emit_scripts(ucd_dir)
+    emit_case_orbits(ucd_dir)
+
+
+main()